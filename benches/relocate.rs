@@ -0,0 +1,85 @@
+//! Compares compacting the population state in place, in one pass, with copying each row of the
+//! distributions through a temporary `Vec` and rebuilding the operators and scores separately,
+//! as `Evolver::prune` used to.
+//!
+//! Run with `cargo bench`.
+extern crate rvlvr;
+
+use std::time::{Duration, Instant};
+use rvlvr::computed_distributions::{ComputedDistributions};
+use rvlvr::operator::{Operator};
+
+const DOMAIN: usize = 16;
+const POPULATION: usize = 100_000;
+const ROUNDS: u32 = 20;
+
+/// The distributions, operators and scores of a population, as an `Evolver` stores them.
+struct Population {
+    dists: ComputedDistributions,
+    operators: Vec<Operator>,
+    scores: Vec<f32>,
+}
+
+/// Keeps every third computed, like a prune which removes two thirds of the population.
+fn relocations() -> Vec<Option<usize>> {
+    (0..POPULATION).map(|i| if i % 3 == 0 { Some(i / 3) } else { None }).collect()
+}
+
+/// Each survivor but the first depends on the survivor before it, so every operator has to be
+/// relocated.
+fn population() -> Population {
+    let mut dists = ComputedDistributions::new(DOMAIN, POPULATION);
+    for i in 0..POPULATION {
+        dists.set_values(i, &[i % DOMAIN]).unwrap();
+    }
+    let operators = (0..POPULATION).map(|i| if i % 3 == 0 && i > 0 { Operator::Not(i - 3) } else { Operator::Value(0) })
+        .collect();
+    let scores = (0..POPULATION).map(|i| i as f32).collect();
+    Population { dists, operators, scores }
+}
+
+fn time<F>(name: &str, mut relocate: F) -> Duration where F: FnMut(&mut Population, &[Option<usize>]) {
+    let relocations = relocations();
+    let mut total = Duration::new(0, 0);
+    for _ in 0..ROUNDS {
+        let mut population = population();
+        let start = Instant::now();
+        relocate(&mut population, &relocations);
+        total += start.elapsed();
+        let last = POPULATION / 3 - 1;
+        assert_eq!(population.dists.read_likely(last).0, last * 3 % DOMAIN);
+        assert_eq!(population.operators[last], Operator::Not(last - 1));
+        assert_eq!(population.scores[last], (last * 3) as f32);
+    }
+    let average = total / ROUNDS;
+    println!("{:>10}: {:?} per relocation of {} computed", name, average, POPULATION);
+    average
+}
+
+fn main() {
+    let copied = time("copied", |population, relocations| {
+        for (i, d) in relocations.iter().enumerate() {
+            if let Some(d) = *d {
+                let dist = population.dists.read(i).to_owned();
+                population.dists.store(d, &dist).unwrap();
+            }
+        }
+        let survivors = relocations.iter().enumerate().filter(|&(_, d)| d.is_some()).map(|(i, _)| i);
+        let operators: Vec<Operator> = survivors.clone()
+            .map(|i| population.operators[i].relocate(relocations).unwrap())
+            .collect();
+        let scores: Vec<f32> = survivors.map(|i| population.scores[i]).collect();
+        population.operators[..operators.len()].copy_from_slice(&operators);
+        population.scores[..scores.len()].copy_from_slice(&scores);
+    });
+    let in_place = time("in place", |population, relocations| {
+        for (i, d) in relocations.iter().enumerate() {
+            if let Some(d) = *d {
+                population.operators[d] = population.operators[i].relocate(relocations).unwrap();
+                population.scores[d] = population.scores[i];
+                population.dists.move_down(i, d);
+            }
+        }
+    });
+    println!("speedup: {:.2}x", copied.as_secs_f64() / in_place.as_secs_f64());
+}
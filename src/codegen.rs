@@ -0,0 +1,135 @@
+//! Generates Rust source code for learned transition functions.
+//!
+//! The generated function takes the current state and returns the next state, with one program
+//! per output variable. Values follow the same rules as `ComputedDistributions`: the domain has
+//! `size` values, and the value `size` means undefined. Each `Ambiguity` asks the `rng` parameter
+//! to choose a value, given the probability of each value.
+use std::fmt::{Write};
+use super::operator::{Operator};
+use super::program::{Program};
+
+/// Emits a function `step(state, rng)` computing output `k` of the next state with
+/// `programs[k]`, over a domain of `size` values.
+pub fn emit_step(programs: &[Program], size: usize) -> String {
+    let mut out = String::new();
+    write_step(&mut out, programs, size).expect("writing to a String cannot fail");
+    out
+}
+
+fn write_step<W>(out: &mut W, programs: &[Program], size: usize) -> ::std::fmt::Result where W: Write {
+    let uses_rng = programs.iter()
+        .any(|p| p.operators().iter().any(|op| matches!(*op, Operator::Ambiguity(_))));
+    let rng = if uses_rng { "rng" } else { "_rng" };
+    writeln!(out, "/// Computes the next state. Values are in `0..{}`, and {} is undefined.", size, size)?;
+    if uses_rng {
+        writeln!(out, "/// `rng` chooses a value given the probability of each value.")?;
+    }
+    writeln!(out, "pub fn step<R>(state: &[usize], {}: &mut R) -> Vec<usize> where R: FnMut(&[f32]) -> usize {{", rng)?;
+    writeln!(out, "    const UNDEFINED: usize = {};", size)?;
+    for (k, program) in programs.iter().enumerate() {
+        writeln!(out, "    // output {}", k)?;
+        for (i, operator) in program.operators().iter().enumerate().skip(program.input_size()) {
+            write!(out, "    let {} = ", name(program, k, i))?;
+            write_operator(out, program, k, *operator, size)?;
+            writeln!(out, ";")?;
+        }
+    }
+    let results: Vec<String> = programs.iter().enumerate().map(|(k, p)| name(p, k, p.root())).collect();
+    writeln!(out, "    vec![{}]", results.join(", "))?;
+    writeln!(out, "}}")
+}
+
+fn write_operator<W>(out: &mut W, program: &Program, k: usize, operator: Operator, size: usize)
+    -> ::std::fmt::Result where W: Write {
+    let n = |x: usize| name(program, k, x);
+    // 1 is undefined if the domain only contains 0.
+    let one = if size > 1 { "1" } else { "UNDEFINED" };
+    match operator {
+        Operator::Initial => panic!("cannot emit Initial operator"),
+        Operator::Value(v) if v >= size => write!(out, "UNDEFINED"),
+        Operator::Value(v) => write!(out, "{}", v),
+        Operator::Ambiguity(c) => write!(out, "::std::cmp::min(rng(&[{}]), UNDEFINED)", c),
+        Operator::Increment(x) => write!(out, "::std::cmp::min({} + 1, UNDEFINED)", n(x)),
+        Operator::Not(x) => write!(out, "if {} == UNDEFINED {{ UNDEFINED }} else if {} != 0 {{ 0 }} else {{ {} }}",
+                                   n(x), n(x), one),
+        Operator::Equality(x, y) =>
+            write!(out, "if {x} == UNDEFINED || {y} == UNDEFINED {{ UNDEFINED }} else if {x} == {y} {{ {one} }} else {{ 0 }}",
+                   x = n(x), y = n(y), one = one),
+        Operator::And(x, y) =>
+            write!(out, "if {x} == UNDEFINED || {y} == UNDEFINED {{ UNDEFINED }} else if {x} != 0 && {y} != 0 {{ {one} }} else {{ 0 }}",
+                   x = n(x), y = n(y), one = one),
+        Operator::Or(x, y) =>
+            write!(out, "if {x} == UNDEFINED || {y} == UNDEFINED {{ UNDEFINED }} else if {x} != 0 || {y} != 0 {{ {one} }} else {{ 0 }}",
+                   x = n(x), y = n(y), one = one),
+        Operator::Ite(x, y, z) =>
+            write!(out, "if {x} == UNDEFINED {{ UNDEFINED }} else if {x} != 0 {{ {y} }} else {{ {z} }}",
+                   x = n(x), y = n(y), z = n(z)),
+    }
+}
+
+/// The expression for computed `i` of the program for output `k`.
+fn name(program: &Program, k: usize, i: usize) -> String {
+    if i < program.input_size() {
+        format!("state[{}]", i)
+    } else {
+        format!("o{}_{}", k, i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::computed_distributions::{ComputedDistributions};
+    use super::super::operator::{Categorical};
+
+    mod generated {
+        include!("../tests/generated/step_122.rs");
+    }
+
+    fn programs_122() -> Vec<Program> {
+        use super::super::operator::Operator::*;
+        vec![
+            // The winner is the player who moves when the counter reaches 2.
+            Program::new(vec![Initial, Initial, Initial, Value(2), Equality(2, 3), Ite(4, 1, 0)], 3).unwrap(),
+            // Players alternate.
+            Program::new(vec![Initial, Initial, Initial, Not(1)], 3).unwrap(),
+            // The counter increments until it reaches 2.
+            Program::new(vec![Initial, Initial, Initial, Value(2), Equality(2, 3), Increment(2), Ite(4, 2, 5)], 3).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn it_emits_the_checked_in_step_function() {
+        assert_eq!(emit_step(&programs_122(), 3), include_str!("../tests/generated/step_122.rs"));
+    }
+
+    #[test]
+    fn it_matches_the_interpreter() {
+        let traces: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]],
+                                       &[&[2, 0, 0], &[2, 1, 2], &[1, 0, 2]]];
+        let programs = programs_122();
+        for trace in traces.iter() {
+            for transition in trace.windows(2) {
+                let generated = generated::step(transition[0], &mut |_: &[f32]| 0);
+                for (k, program) in programs.iter().enumerate() {
+                    let mut dists = ComputedDistributions::new(3, program.len());
+                    dists.set_values(0, transition[0]).unwrap();
+                    for (i, operator) in program.operators().iter().enumerate().skip(program.input_size()) {
+                        operator.run(i, &mut dists).unwrap();
+                    }
+                    assert_eq!(generated[k], dists.read_likely(program.root()).0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_emits_ambiguity_as_rng() {
+        use super::super::operator::Operator::*;
+        let program = Program::new(vec![Initial, Ambiguity(Categorical::COIN), Not(1)], 1).unwrap();
+        let source = emit_step(&[program], 1);
+        assert!(source.contains("/// `rng` chooses a value given the probability of each value."));
+        assert!(source.contains("let o0_1 = ::std::cmp::min(rng(&[0.5, 0.5]), UNDEFINED);"));
+        assert!(source.contains("let o0_2 = if o0_1 == UNDEFINED { UNDEFINED } else if o0_1 != 0 { 0 } else { UNDEFINED };"));
+    }
+}
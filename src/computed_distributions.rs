@@ -1,52 +1,150 @@
-///! Stores an array of probability distributions over a finite, non-negative integer domain.
-///! Each such distribution is called a "computed distribution." These distributions correspond to
-///! the computed probability distributions for the corresponding "computed."
-use std;
+//! Stores an array of probability distributions over a finite, non-negative integer domain.
+//! Each such distribution is called a "computed distribution." These distributions correspond to
+//! the computed probability distributions for the corresponding "computed."
+//!
+//! # Undefined values
+//!
+//! A computed over a domain of `size` values may also be undefined, for example when it
+//! increments the largest value in the domain. Each distribution therefore has `size + 1`
+//! entries, and the last one holds the probability that the computed is undefined. All kernels
+//! follow the same rules, so that every output distribution sums to the same mass as the joint
+//! distribution of its inputs:
+//!
+//! * Any value an operator produces outside of the domain is undefined.
+//! * By default, undefined propagates: if any input is undefined, so is the output. Operators
+//!   using `compute_at` and `compute_at_prob` never see undefined inputs.
+//! * Operators which don't always need all of their inputs, such as `Ite` with an untaken
+//!   branch, can use `compute_at_lazy`, which passes the undefined value to the operator as
+//!   `size`.
 
+use std::fmt::{Debug, Display};
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub};
+use super::error::{Error, Result};
+
+/// A floating point type used to store probabilities.
+pub trait Probability: Copy + Debug + Display + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
+    + Div<Output = Self> + AddAssign + MulAssign + DivAssign {
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! impl_probability {
+    ($t:ty) => {
+        impl Probability for $t {
+            fn zero() -> Self {
+                0.0
+            }
+
+            fn one() -> Self {
+                1.0
+            }
+
+            fn from_f64(x: f64) -> Self {
+                x as $t
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+    }
+}
+
+impl_probability!(f32);
+impl_probability!(f64);
+
+/// The most sources a kernel takes. Operators take at most three, leaving room for wider ones.
+/// Kernels keep their arguments in arrays of this size, so that they never allocate.
+pub const MAX_ARGS: usize = 8;
+
+/// Distributions are stored as `f32` unless another precision is requested with
+/// `with_precision`.
 #[derive(Clone, Debug)]
-pub struct ComputedDistributions {
+pub struct ComputedDistributions<P = f32> {
     size: usize,
-    values: Vec<f32>,
+    values: Vec<P>,
 }
 
 impl ComputedDistributions {
     pub fn new(size: usize, count: usize) -> Self {
+        ComputedDistributions::with_precision(size, count)
+    }
+}
+
+impl<P> ComputedDistributions<P> where P: Probability {
+    pub fn with_precision(size: usize, count: usize) -> Self {
         ComputedDistributions {
-            size: size,
-            values: std::iter::repeat(0.0).take((size + 1) * count).collect(),
+            size,
+            values: vec![P::zero(); (size + 1) * count],
         }
     }
 
-    pub fn set_values(&mut self, offset: usize, values: &[usize]) {
+    /// Sets computed `offset..offset + values.len()` to always have the given values, which must
+    /// be in the domain.
+    pub fn set_values(&mut self, offset: usize, values: &[usize]) -> Result<()> {
+        if let Some(&value) = values.iter().find(|&&value| value >= self.size) {
+            return Err(Error::ValueOutOfDomain { value, size: self.size });
+        }
+        if !values.is_empty() {
+            self.check_bounds(offset + values.len() - 1)?;
+        }
         for (i, val) in values.iter().enumerate() {
-            let mut slice = self.read_mut(offset + i);
+            let slice = self.read_mut(offset + i);
             for (j, v) in slice.iter_mut().enumerate() {
                 if *val == j {
-                    *v = 1.0;
+                    *v = P::one();
                 } else {
-                    *v = 0.0;
+                    *v = P::zero();
                 }
             }
         }
+        Ok(())
+    }
+
+    /// The number of defined values in the domain. Index `size` of each distribution holds the
+    /// probability that the computed is undefined.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of computed distributions stored.
+    pub fn count(&self) -> usize {
+        self.values.len() / (self.size + 1)
     }
 
     fn computed_idx(&self, computed: usize) -> usize {
         computed * (self.size + 1)
     }
 
-    pub fn store(&mut self, computed: usize, distribution: &[f32]) {
-        assert_eq!(distribution.len(), self.size + 1);
-        let idx = self.computed_idx(computed);
-        copy_into_slice(&mut self.values, idx, distribution);
+    /// Replaces the distribution of a computed, which must have one entry per value plus one for
+    /// undefined.
+    pub fn store(&mut self, computed: usize, distribution: &[P]) -> Result<()> {
+        if distribution.len() != self.size + 1 {
+            return Err(Error::DistributionLength { expected: self.size + 1, found: distribution.len() });
+        }
+        self.check_bounds(computed)?;
+        self.read_mut(computed).copy_from_slice(distribution);
+        Ok(())
+    }
+
+    /// Checks that a computed has a distribution.
+    pub fn check_bounds(&self, computed: usize) -> Result<()> {
+        if computed < self.count() {
+            Ok(())
+        } else {
+            Err(Error::OutOfBounds { computed, count: self.count() })
+        }
     }
 
-    pub fn read(&self, computed: usize) -> &[f32] {
+    pub fn read(&self, computed: usize) -> &[P] {
         self.values.split_at(self.computed_idx(computed)).1.split_at(self.size + 1).0
     }
 
-    pub fn read_likely(&self, computed: usize) -> (usize, f32) {
+    pub fn read_likely(&self, computed: usize) -> (usize, P) {
         let mut best = 0;
-        let mut best_prob = 0.0;
+        let mut best_prob = P::zero();
         for (v, &pv) in self.read(computed).iter().enumerate() {
             if pv > best_prob {
                 best_prob = pv;
@@ -56,191 +154,308 @@ impl ComputedDistributions {
         (best, best_prob)
     }
 
-    pub fn read_mut(&mut self, computed: usize) -> &mut[f32] {
+    pub fn read_mut(&mut self, computed: usize) -> &mut[P] {
         let idx = self.computed_idx(computed);
         let size = self.size;
         self.values.split_at_mut(idx).1.split_at_mut(size + 1).0
     }
 
-    pub fn relocate(&mut self, relocations: &[Option<usize>]) {
-        for (i, d) in relocations.iter().enumerate() {
-            if let &Some(d) = d {
-                // Is there a clean way to avoid this allocation?
-                let dist = self.read(i).to_owned();
-                self.store(d, &dist);
+    /// The total probability of a computed distribution, which should be one.
+    pub fn total(&self, computed: usize) -> P {
+        self.read(computed).iter().fold(P::zero(), |total, &p| total + p)
+    }
+
+    /// How far the total probability of a computed distribution is from one.
+    pub fn drift(&self, computed: usize) -> f64 {
+        (self.total(computed).to_f64() - 1.0).abs()
+    }
+
+    /// Rescales a computed distribution so that it sums to one.
+    pub fn renormalize(&mut self, computed: usize) {
+        let total = self.total(computed);
+        if total > P::zero() {
+            for p in self.read_mut(computed).iter_mut() {
+                *p /= total;
             }
         }
     }
 
-    pub fn compute_at_3<F>(&mut self, target: usize, srcs: (usize, usize, usize), f: F)
-        where F: Fn(usize, usize, usize) -> usize {
-        let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(srcs.0);
-        let y_idx = self.computed_idx(srcs.1);
-        let z_idx = self.computed_idx(srcs.2);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
-        }
-        // If any input in undefined, the output is undefined.
-        self.values[target_idx + self.size] += self.values[x_idx + self.size];
-        self.values[target_idx + self.size] += self.values[y_idx + self.size];
-        self.values[target_idx + self.size] += self.values[z_idx + self.size];
-        // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size)).enumerate() {
-            for (y, yi) in (y_idx..(y_idx + self.size)).enumerate() {
-                for (z, zi) in (z_idx..(z_idx + self.size)).enumerate() {
-                    let mut out = f(x, y, z);
-                    // If out is too large, then it's not defined.
-                    if out >= self.size {
-                        out = self.size;
-                    }
-                    // Add the probability that all three computed have these values.
-                    self.values[target_idx + out] += self.values[xi] * self.values[yi] * self.values[zi];
+    /// Moves the distribution of each computed `i` to `relocations[i]`, in place. Computed may
+    /// only move to lower indices, so every distribution is moved before it is overwritten.
+    pub fn relocate(&mut self, relocations: &[Option<usize>]) -> Result<()> {
+        for (i, d) in relocations.iter().enumerate() {
+            if let &Some(d) = d {
+                if d > i {
+                    return Err(Error::UnorderedRelocation { computed: i, relocation: d });
                 }
+                self.check_bounds(i)?;
+                self.move_down(i, d);
             }
         }
+        Ok(())
     }
 
-    pub fn compute_at_2<F>(&mut self, target: usize, srcs: (usize, usize), f: F)
-        where F: Fn(usize, usize) -> usize {
-        let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(srcs.0);
-        let y_idx = self.computed_idx(srcs.1);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
+    /// Copies the distribution of `from` over the distribution of `to`, which must not be after
+    /// it.
+    pub fn move_down(&mut self, from: usize, to: usize) {
+        debug_assert!(to <= from, "cannot move computed {} up to {}", from, to);
+        if from != to {
+            let start = self.computed_idx(from);
+            let end = start + self.size + 1;
+            let dest = self.computed_idx(to);
+            self.values.copy_within(start..end, dest);
         }
-        // If any input in undefined, the output is undefined.
-        self.values[target_idx + self.size] += self.values[x_idx + self.size];
-        self.values[target_idx + self.size] += self.values[y_idx + self.size];
-        // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size)).enumerate() {
-            for (y, yi) in (y_idx..(y_idx + self.size)).enumerate() {
-                let mut out = f(x, y);
-                // If out is too large, then it's not defined.
-                if out >= self.size {
-                    out = self.size;
-                }
-                // Add the probability that all three computed have these values.
-                self.values[target_idx + out] += self.values[xi] * self.values[yi];
+    }
+
+    /// Computes the distribution of `f` applied to the values of the `srcs` computed, assuming
+    /// that they are independent. If any input is undefined, the output is undefined. There may
+    /// be at most `MAX_ARGS` sources.
+    pub fn compute_at<F>(&mut self, target: usize, srcs: &[usize], f: F)
+        where F: Fn(&[usize]) -> usize {
+        let size = self.size;
+        self.compute_at_lazy(target, srcs, |args| {
+            if args.iter().any(|&arg| arg >= size) {
+                size
+            } else {
+                f(args)
             }
-        }
+        });
     }
 
-    pub fn compute_at_1<F>(&mut self, target: usize, src: usize, f: F)
-        where F: Fn(usize) -> usize {
+    /// Like `compute_at`, but `f` is also called when inputs are undefined, in which case they
+    /// have the value `size()`.
+    pub fn compute_at_lazy<F>(&mut self, target: usize, srcs: &[usize], f: F)
+        where F: Fn(&[usize]) -> usize {
         let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(src);
+        let mut src_idxs = [0; MAX_ARGS];
+        let src_idxs = self.source_indices(srcs, &mut src_idxs);
         // Clear out all target values.
         for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
+            self.values[t] = P::zero();
         }
-        // If any input in undefined, the output is undefined.
-        self.values[target_idx + self.size] += self.values[x_idx + self.size];
         // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size)).enumerate() {
-            let mut out = f(x);
+        let mut args = [0; MAX_ARGS];
+        let args = &mut args[..srcs.len()];
+        loop {
+            let mut out = f(args);
             // If out is too large, then it's not defined.
             if out >= self.size {
                 out = self.size;
             }
-            // Add the probability that all three computed have these values.
-            self.values[target_idx + out] += self.values[xi];
+            // Add the probability that all computed have these values.
+            let mut prob = P::one();
+            for (&src_idx, &arg) in src_idxs.iter().zip(args.iter()) {
+                prob *= self.values[src_idx + arg];
+            }
+            self.values[target_idx + out] += prob;
+            if !next_args(args, self.size + 1) {
+                break;
+            }
         }
     }
 
-    pub fn compute_at_0<F>(&mut self, target: usize, f: F)
-        where F: Fn() -> usize {
+    /// Calls `f` with the target distribution for each combination of defined values of the
+    /// `srcs` computed, along with the probability of each value. `f` should add the probability
+    /// of each output to the target distribution, using index `size()` for undefined outputs.
+    /// If any input is undefined, the output is undefined.
+    pub fn compute_at_prob<F>(&mut self, target: usize, srcs: &[usize], f: F)
+        where F: Fn(&mut[P], &[usize], &[P]) {
         let target_idx = self.computed_idx(target);
+        let mut src_idxs = [0; MAX_ARGS];
+        let src_idxs = self.source_indices(srcs, &mut src_idxs);
         // Clear out all target values.
         for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
+            self.values[t] = P::zero();
+        }
+        // For each combination of inputs, compute the output value.
+        let mut args = [0; MAX_ARGS];
+        let args = &mut args[..srcs.len()];
+        let mut probs = [P::zero(); MAX_ARGS];
+        let probs = &mut probs[..srcs.len()];
+        loop {
+            for ((p, &src_idx), &arg) in probs.iter_mut().zip(src_idxs.iter()).zip(args.iter()) {
+                *p = self.values[src_idx + arg];
+            }
+            if args.iter().any(|&arg| arg >= self.size) {
+                // If any input in undefined, the output is undefined.
+                self.values[target_idx + self.size] += probs.iter().fold(P::one(), |a, &b| a * b);
+            } else {
+                f(self.read_mut(target), args, probs);
+            }
+            if !next_args(args, self.size + 1) {
+                break;
+            }
         }
-        let mut out = f();
-        // If out is too large, then it's not defined.
-        if out >= self.size {
-            out = self.size;
+    }
+
+    /// Writes the index of each source computed's distribution into `idxs`, returning the
+    /// filled part. There are at most `MAX_ARGS` sources.
+    fn source_indices<'a>(&self, srcs: &[usize], idxs: &'a mut [usize; MAX_ARGS]) -> &'a [usize] {
+        assert!(srcs.len() <= MAX_ARGS, "kernels take at most {} arguments, not {}", MAX_ARGS, srcs.len());
+        for (idx, &src) in idxs.iter_mut().zip(srcs.iter()) {
+            *idx = self.computed_idx(src);
         }
-        self.values[target_idx + out] = 1.0;
+        &idxs[..srcs.len()]
+    }
+
+    pub fn compute_at_3<F>(&mut self, target: usize, srcs: (usize, usize, usize), f: F)
+        where F: Fn(usize, usize, usize) -> usize {
+        self.compute_at(target, &[srcs.0, srcs.1, srcs.2], |args| f(args[0], args[1], args[2]));
+    }
+
+    pub fn compute_at_2<F>(&mut self, target: usize, srcs: (usize, usize), f: F)
+        where F: Fn(usize, usize) -> usize {
+        self.compute_at(target, &[srcs.0, srcs.1], |args| f(args[0], args[1]));
+    }
+
+    pub fn compute_at_1<F>(&mut self, target: usize, src: usize, f: F)
+        where F: Fn(usize) -> usize {
+        self.compute_at(target, &[src], |args| f(args[0]));
+    }
+
+    pub fn compute_at_0<F>(&mut self, target: usize, f: F)
+        where F: Fn() -> usize {
+        self.compute_at(target, &[], |_| f());
     }
 
     pub fn compute_at_0_prob<F>(&mut self, target: usize, f: F)
-        where F: Fn(&mut[f32]) {
-        let target_idx = self.computed_idx(target);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
-        }
-        f(self.read_mut(target));
+        where F: Fn(&mut[P]) {
+        self.compute_at_prob(target, &[], |out, _, _| f(out));
     }
 
     pub fn compute_at_1_prob<F>(&mut self, target: usize, src: usize, f: F)
-        where F: Fn(&mut[f32], usize, f32) {
-        let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(src);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
-        }
-        // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size + 1)).enumerate() {
-            let px = self.values[xi];
-            f(self.read_mut(target), x, px);
-        }
+        where F: Fn(&mut[P], usize, P) {
+        self.compute_at_prob(target, &[src], |out, args, probs| f(out, args[0], probs[0]));
     }
 
     pub fn compute_at_2_prob<F>(&mut self, target: usize, srcs: (usize, usize), f: F)
-        where F: Fn(&mut[f32], usize, f32, usize, f32) {
-        let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(srcs.0);
-        let y_idx = self.computed_idx(srcs.1);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
+        where F: Fn(&mut[P], usize, P, usize, P) {
+        self.compute_at_prob(target, &[srcs.0, srcs.1],
+            |out, args, probs| f(out, args[0], probs[0], args[1], probs[1]));
+    }
+
+    pub fn compute_at_3_prob<F>(&mut self, target: usize, srcs: (usize, usize, usize), f: F)
+        where F: Fn(&mut[P], usize, P, usize, P, usize, P) {
+        self.compute_at_prob(target, &[srcs.0, srcs.1, srcs.2],
+            |out, args, probs| f(out, args[0], probs[0], args[1], probs[1], args[2], probs[2]));
+    }
+}
+
+/// Steps `args` to the next combination of values below `limit`, with the last argument changing
+/// fastest. Returns false once all combinations have been visited.
+fn next_args(args: &mut [usize], limit: usize) -> bool {
+    for arg in args.iter_mut().rev() {
+        *arg += 1;
+        if *arg < limit {
+            return true;
         }
-        // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size + 1)).enumerate() {
-            for (y, yi) in (y_idx..(y_idx + self.size + 1)).enumerate() {
-                let px = self.values[xi];
-                let py = self.values[yi];
-                f(self.read_mut(target), x, px, y, py);
+        *arg = 0;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use super::*;
+
+    #[test]
+    fn it_computes_any_arity() {
+        let mut distributions = ComputedDistributions::new(2, 5);
+        distributions.store(0, &[0.5, 0.5, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 1.0, 0.0]).unwrap();
+        distributions.store(3, &[0.0, 1.0, 0.0]).unwrap();
+        distributions.compute_at(4, &[0, 1, 2, 3], |args| {
+            if args[0] != 0 && args[3] != 0 {
+                args[1]
+            } else {
+                args[2]
             }
-        }
+        });
+        assert_eq!(distributions.read(4), &[0.5, 0.5, 0.0]);
     }
 
-    pub fn compute_at_3_prob<F>(&mut self, target: usize, srcs: (usize, usize, usize), f: F)
-        where F: Fn(&mut[f32], usize, f32, usize, f32, usize, f32) {
-        let target_idx = self.computed_idx(target);
-        let x_idx = self.computed_idx(srcs.0);
-        let y_idx = self.computed_idx(srcs.1);
-        let z_idx = self.computed_idx(srcs.2);
-        // Clear out all target values.
-        for t in target_idx..(target_idx + self.size + 1) {
-            self.values[t] = 0.0;
+    #[test]
+    fn it_computes_any_arity_with_probabilities() {
+        let mut distributions = ComputedDistributions::new(2, 5);
+        distributions.store(0, &[0.5, 0.5, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 0.5, 0.5]).unwrap();
+        distributions.store(3, &[0.0, 1.0, 0.0]).unwrap();
+        distributions.compute_at_prob(4, &[0, 1, 2, 3], |out, args, probs| {
+            let p: f32 = probs.iter().product();
+            out[usize::min(args[0] + args[2], 2)] += p;
+        });
+        assert_eq!(distributions.read(4), &[0.0, 0.25, 0.75]);
+    }
+
+    #[test]
+    fn it_relocates_in_place() {
+        let mut distributions = ComputedDistributions::new(2, 4);
+        distributions.set_values(0, &[0, 1, 1, 0]).unwrap();
+        distributions.store(2, &[0.0, 0.0, 1.0]).unwrap();
+        distributions.relocate(&[Some(0), None, Some(1), Some(2)]).unwrap();
+        assert_eq!(distributions.read(1), &[0.0, 0.0, 1.0]);
+        assert_eq!(distributions.read(2), &[1.0, 0.0, 0.0]);
+        assert_eq!(distributions.relocate(&[Some(1)]), Err(Error::UnorderedRelocation { computed: 0, relocation: 1 }));
+    }
+
+    #[test]
+    fn it_renormalizes() {
+        let mut distributions = ComputedDistributions::<f64>::with_precision(2, 1);
+        distributions.store(0, &[0.25, 0.5, 0.25]).unwrap();
+        assert_eq!(distributions.drift(0), 0.0);
+        distributions.store(0, &[0.5, 0.5, 0.5]).unwrap();
+        assert_eq!(distributions.drift(0), 0.5);
+        distributions.renormalize(0);
+        assert!(distributions.drift(0) < 1e-12);
+        assert!((distributions.read(0)[0] - 1.0 / 3.0).abs() < 1e-12);
+    }
+
+    fn random_distribution<R>(rand_gen: &mut R, size: usize) -> Vec<f32> where R: Rng {
+        let mut distribution: Vec<f32> = (0..(size + 1)).map(|_| rand_gen.gen::<f32>()).collect();
+        // Leave some values impossible, as one-hot inputs are common.
+        for p in distribution.iter_mut() {
+            if rand_gen.gen::<f32>() < 0.3 {
+                *p = 0.0;
+            }
         }
-        // For each combination of inputs, compute the output value.
-        for (x, xi) in (x_idx..(x_idx + self.size + 1)).enumerate() {
-            for (y, yi) in (y_idx..(y_idx + self.size + 1)).enumerate() {
-                for (z, zi) in (z_idx..(z_idx + self.size + 1)).enumerate() {
-                    let px = self.values[xi];
-                    let py = self.values[yi];
-                    let pz = self.values[zi];
-                    f(self.read_mut(target), x, px, y, py, z, pz);
-                }
+        let total: f32 = distribution.iter().sum();
+        if total == 0.0 {
+            distribution[size] = 1.0;
+        } else {
+            for p in distribution.iter_mut() {
+                *p /= total;
             }
         }
+        distribution
     }
-}
 
-fn copy_into_slice<T>(dest: &mut [T], offset: usize, src: &[T]) where T: Clone {
-    let dlen = dest.len();
-    let slen = src.len();
-    if offset + slen > dlen {
-        panic!("Destination is not large enough");
-    } else {
-        for (s, d) in (offset..(offset + slen)).enumerate() {
-            dest[d] = src[s].clone();
+    #[test]
+    fn it_preserves_mass_in_all_kernels() {
+        let mut rand_gen = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for _ in 0..200 {
+            let size = 1 + rand_gen.gen_range(0, 4);
+            let mut distributions = ComputedDistributions::new(size, 7);
+            for i in 0..4 {
+                let distribution = random_distribution(&mut rand_gen, size);
+                distributions.store(i, &distribution).unwrap();
+            }
+            let srcs: Vec<usize> = (0..rand_gen.gen_range(0, 5)).map(|_| rand_gen.gen_range(0, 4)).collect();
+            // A random function of the inputs, which sometimes leaves the domain.
+            let table: Vec<usize> = (0..64).map(|_| rand_gen.gen_range(0, size + 2)).collect();
+            let f = |args: &[usize]| table[args.iter().fold(0, |h, &a| (h * 7 + a) % 64)];
+            distributions.compute_at(4, &srcs, f);
+            distributions.compute_at_lazy(5, &srcs, f);
+            distributions.compute_at_prob(6, &srcs, |out, args, probs| {
+                let p: f32 = probs.iter().product();
+                out[usize::min(f(args), size)] += p;
+            });
+            for i in 4..7 {
+                let total: f32 = distributions.read(i).iter().sum();
+                assert!((total - 1.0).abs() < 1e-5, "{} sums to {}", i, total);
+            }
         }
     }
 }
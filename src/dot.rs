@@ -0,0 +1,105 @@
+//! Exports programs and populations as Graphviz DOT graphs.
+//!
+//! Unlike the textual printers, each computed appears exactly once, so sharing in the DAG is
+//! visible. Edges point from each computed to the operators which use it.
+use std::fmt::{Write, Result};
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::operator::{Operator};
+use super::program::{Program};
+
+/// Writes the computed in `nodes` as a DOT graph, labelling each with its index and operator,
+/// and optionally with its score and distribution. Every dependent of a node must also be in
+/// `nodes`. The last node is drawn as the root.
+pub fn write_dot<W, P>(out: &mut W, operators: &[Operator], nodes: &[usize], scores: Option<&[f32]>,
+                       distributions: Option<&ComputedDistributions<P>>) -> Result
+    where W: Write, P: Probability {
+    writeln!(out, "digraph program {{")?;
+    for (n, &i) in nodes.iter().enumerate() {
+        write!(out, "    n{} [label=\"{}: {}", i, i, label(operators, i))?;
+        if let Some(scores) = scores {
+            write!(out, "\\nscore {}", scores[i])?;
+        }
+        if let Some(distributions) = distributions {
+            let probs: Vec<String> = distributions.read(i).iter().map(|p| format!("{:.3}", p)).collect();
+            write!(out, "\\n[{}]", probs.join(", "))?;
+        }
+        write!(out, "\"")?;
+        if operators[i] == Operator::Initial {
+            write!(out, ", shape=box")?;
+        }
+        if n + 1 == nodes.len() {
+            write!(out, ", peripheries=2")?;
+        }
+        writeln!(out, "];")?;
+    }
+    for &i in nodes.iter() {
+        let deps = operators[i].dependents();
+        let arity = deps.iter().filter(|d| d.is_some()).count();
+        for (a, dep) in deps.iter().enumerate() {
+            if let Some(x) = *dep {
+                match (operators[i], arity) {
+                    (Operator::Ite(_, _, _), _) => {
+                        writeln!(out, "    n{} -> n{} [label=\"{}\"];", x, i, ["if", "then", "else"][a])?;
+                    },
+                    (_, 1) => writeln!(out, "    n{} -> n{};", x, i)?,
+                    _ => writeln!(out, "    n{} -> n{} [label=\"{}\"];", x, i, a)?,
+                }
+            }
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// Writes a program as a DOT graph.
+pub fn write_program_dot<W>(out: &mut W, program: &Program) -> Result where W: Write {
+    let nodes: Vec<usize> = (0..program.len()).filter(|&i| i != program.root()).chain(Some(program.root())).collect();
+    write_dot::<W, f32>(out, program.operators(), &nodes, None, None)
+}
+
+fn label(operators: &[Operator], i: usize) -> String {
+    match operators[i] {
+        Operator::Initial => format!("input[{}]", i),
+        Operator::Value(v) => format!("Value({})", v),
+        Operator::Ambiguity(c) => format!("Ambiguity({})", c),
+        operator => operator.name().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_writes_shared_nodes_once() {
+        use super::super::operator::Operator::*;
+        let program = Program::new(vec![Initial, Initial, Equality(0, 1), Not(2), Ite(2, 3, 0)], 2).unwrap();
+        let mut dot = String::new();
+        write_program_dot(&mut dot, &program).unwrap();
+        assert_eq!(dot, "digraph program {
+    n0 [label=\"0: input[0]\", shape=box];
+    n1 [label=\"1: input[1]\", shape=box];
+    n2 [label=\"2: Equality\"];
+    n3 [label=\"3: Not\"];
+    n4 [label=\"4: Ite\", peripheries=2];
+    n0 -> n2 [label=\"0\"];
+    n1 -> n2 [label=\"1\"];
+    n2 -> n3;
+    n2 -> n4 [label=\"if\"];
+    n3 -> n4 [label=\"then\"];
+    n0 -> n4 [label=\"else\"];
+}
+");
+    }
+
+    #[test]
+    fn it_writes_scores_and_distributions() {
+        use super::super::operator::Operator::*;
+        let operators = [Initial, Not(0)];
+        let mut dists = ComputedDistributions::new(2, 2);
+        dists.set_values(0, &[1]).unwrap();
+        operators[1].run(1, &mut dists).unwrap();
+        let mut dot = String::new();
+        write_dot(&mut dot, &operators, &[0, 1], Some(&[0.0, 2.5]), Some(&dists)).unwrap();
+        assert!(dot.contains("n1 [label=\"1: Not\\nscore 2.5\\n[1.000, 0.000, 0.000]\", peripheries=2];"));
+    }
+}
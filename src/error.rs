@@ -0,0 +1,158 @@
+//! Errors reported when the library is given invalid input.
+use std::error;
+use std::fmt;
+use std::result;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// `Initial` operators only mark inputs, so they can't be run or relocated.
+    InitialOperator,
+    /// An operator refers to a computed which has no relocation.
+    MissingRelocation { computed: usize },
+    /// A computed would be relocated to a later index.
+    UnorderedRelocation { computed: usize, relocation: usize },
+    /// A computed index is past the end of the distributions.
+    OutOfBounds { computed: usize, count: usize },
+    /// A distribution doesn't have one entry per value, plus one for undefined.
+    DistributionLength { expected: usize, found: usize },
+    /// A program has no operators.
+    EmptyProgram,
+    /// An `Initial` operator isn't among the first `input_size` operators, or an input isn't
+    /// `Initial`.
+    MisplacedInitial { computed: usize },
+    /// An operator refers to an operator at or after itself.
+    ForwardReference { computed: usize, dependent: usize },
+    /// A program has a different number of inputs than the samples.
+    InputSize { expected: usize, found: usize },
+    /// There is not enough room left in the population.
+    PopulationFull,
+    /// A value is too large for the domain of `size` values.
+    ValueOutOfDomain { value: usize, size: usize },
+    /// There are no samples to learn from.
+    NoSamples,
+    /// State `state` of sample `sample` has no values.
+    EmptyState { sample: usize, state: usize },
+    /// State `state` of sample `sample` has a different number of values than the first state.
+    StateWidth { sample: usize, state: usize, expected: usize, found: usize },
+    /// State `state` of sample `sample` has a value larger than `max_value`.
+    StateValue { sample: usize, state: usize, value: usize, max_value: usize },
+    /// No sample has more than one state, so there are no transitions to learn from.
+    NoTransitions,
+    /// The population has no room for any computed after the inputs.
+    PopulationTooSmall { population_size: usize, input_size: usize },
+    /// An operator's arguments don't have the types of its signature.
+    IllTyped { computed: usize },
+    /// There is no output with this index.
+    NoSuchOutput { output: usize, output_size: usize },
+    /// A computed index isn't among the evaluated computed of the population.
+    NoSuchComputed { computed: usize, count: usize },
+    /// There is no sample transition with this index.
+    NoSuchSample { sample: usize, sample_count: usize },
+    /// An evaluated distribution drifted from one by more than the `Validate` tolerance.
+    Drift { sample: usize, computed: usize, drift: f64 },
+    /// Writing the output failed.
+    Write,
+    /// There is no grammar, or the grammar has no symbol with this name.
+    NoSuchSymbol { name: String },
+    /// There is no input with this index.
+    NoSuchInput { input: usize, input_size: usize },
+    /// No computed in the population may be a program for this output.
+    NoProgram { output: usize },
+    /// A random choice can't be fitted over a domain of `size` values, since a `Categorical` only
+    /// holds `max_size`.
+    DomainTooLarge { size: usize, max_size: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InitialOperator => write!(f, "Initial operators cannot be run or relocated"),
+            Error::MissingRelocation { computed } => write!(f, "computed {} was not relocated", computed),
+            Error::UnorderedRelocation { computed, relocation } =>
+                write!(f, "computed {} cannot be relocated to later index {}", computed, relocation),
+            Error::OutOfBounds { computed, count } =>
+                write!(f, "computed {} is out of bounds for {} distributions", computed, count),
+            Error::DistributionLength { expected, found } =>
+                write!(f, "expected a distribution of length {}, found {}", expected, found),
+            Error::EmptyProgram => write!(f, "program has no operators"),
+            Error::MisplacedInitial { computed } =>
+                write!(f, "operator {}: Initial operators must come first, and only be inputs", computed),
+            Error::ForwardReference { computed, dependent } =>
+                write!(f, "operator {} refers to later operator {}", computed, dependent),
+            Error::InputSize { expected, found } => write!(f, "expected {} inputs, found {}", expected, found),
+            Error::PopulationFull => write!(f, "not enough room in the population"),
+            Error::ValueOutOfDomain { value, size } => write!(f, "value {} is outside the domain 0..{}", value, size),
+            Error::NoSamples => write!(f, "no samples"),
+            Error::EmptyState { sample, state } => write!(f, "sample {}, state {}: state has no values", sample, state),
+            Error::StateWidth { sample, state, expected, found } =>
+                write!(f, "sample {}, state {}: expected {} values, found {}", sample, state, expected, found),
+            Error::StateValue { sample, state, value, max_value } =>
+                write!(f, "sample {}, state {}: value {} is larger than the maximum value {}", sample, state, value,
+                       max_value),
+            Error::NoTransitions => write!(f, "no sample has a transition between two states"),
+            Error::PopulationTooSmall { population_size, input_size } =>
+                write!(f, "population of {} has no room after {} inputs", population_size, input_size),
+            Error::IllTyped { computed } => write!(f, "operator {} is ill-typed", computed),
+            Error::NoSuchOutput { output, output_size } =>
+                write!(f, "output {} does not exist, there are {} outputs", output, output_size),
+            Error::NoSuchComputed { computed, count } =>
+                write!(f, "computed {} does not exist, there are {} evaluated computed", computed, count),
+            Error::NoSuchSample { sample, sample_count } =>
+                write!(f, "sample {} does not exist, there are {} samples", sample, sample_count),
+            Error::Drift { sample, computed, drift } =>
+                write!(f, "distribution of computed {} in sample {} drifted from one by {}", computed, sample, drift),
+            Error::Write => write!(f, "failed to write the output"),
+            Error::NoSuchSymbol { ref name } => write!(f, "the grammar has no symbol `{}`", name),
+            Error::NoSuchInput { input, input_size } =>
+                write!(f, "input {} does not exist, there are {} inputs", input, input_size),
+            Error::NoProgram { output } => write!(f, "no computed may be a program for output {}", output),
+            Error::DomainTooLarge { size, max_size } =>
+                write!(f, "random choices hold at most {} values, but the domain has {}", max_size, size),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// A broken invariant of the population in an `Evolver`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// A per-computed array doesn't have one entry per computed in the population.
+    Length { array: &'static str, expected: usize, found: usize },
+    /// An input isn't an `Initial` operator.
+    InputNotInitial { computed: usize },
+    /// A surviving computed after the inputs is an `Initial` operator.
+    InitialAfterInputs { computed: usize },
+    /// An operator refers to an operator at or after itself.
+    ForwardReference { computed: usize, dependent: usize },
+    /// A surviving computed doesn't have the type inferred from its operator.
+    IllTyped { computed: usize },
+    /// An input was relocated, or a computed was relocated to a later index, past the survivors,
+    /// or ahead of an earlier survivor.
+    Relocation { computed: usize, relocation: usize },
+    /// An evaluated distribution doesn't sum to one.
+    Drift { sample: usize, computed: usize, drift: f64 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::Length { array, expected, found } =>
+                write!(f, "{} has {} entries, but the population has {} computed", array, found, expected),
+            Violation::InputNotInitial { computed } => write!(f, "input {} is not an Initial operator", computed),
+            Violation::InitialAfterInputs { computed } =>
+                write!(f, "computed {} is an Initial operator, but is not an input", computed),
+            Violation::ForwardReference { computed, dependent } =>
+                write!(f, "operator {} refers to later operator {}", computed, dependent),
+            Violation::IllTyped { computed } => write!(f, "computed {} does not have its inferred type", computed),
+            Violation::Relocation { computed, relocation } =>
+                write!(f, "computed {} is relocated to {}", computed, relocation),
+            Violation::Drift { sample, computed, drift } =>
+                write!(f, "distribution of computed {} in sample {} drifted from one by {}", computed, sample, drift),
+        }
+    }
+}
+
+impl error::Error for Violation {}
+
+pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,158 @@
+//! Scores fixed programs on sample traces, without evolving anything.
+//!
+//! Each program is evaluated analytically on every transition of the traces, exactly as the
+//! `Evolver` evaluates its population, and is scored with the same metrics.
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::error::{Error, Result};
+use super::program::{Program};
+use super::score::{compute_score_for_output, complexity, complexity_score, log_mse_score, portion_correct_score,
+                   Complexity};
+
+/// How well a program predicts one output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    /// The combined score, as computed by `compute_score_for_output`.
+    pub score: f32,
+    pub portion_correct: f32,
+    pub log_mse: f32,
+    pub complexity: f32,
+    /// The most likely value predicted for each transition, in the order of the traces.
+    pub predictions: Vec<Prediction>,
+    /// `confusion[target][predicted]` counts the transitions with each pair of values. Both
+    /// indices include `max_value + 1`, for undefined values.
+    pub confusion: Vec<Vec<usize>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prediction {
+    pub value: usize,
+    pub probability: f32,
+    pub target: usize,
+}
+
+/// Checks that there is at least one transition, and that every state has the same number of
+/// values, all at most `max_value`. Returns the number of values in each state.
+pub fn validate_samples(samples: &[&[&[usize]]], max_value: usize) -> Result<usize> {
+    let width = samples.iter().flat_map(|sample| sample.first()).next().ok_or(Error::NoSamples)?.len();
+    for (s, sample) in samples.iter().enumerate() {
+        for (t, state) in sample.iter().enumerate() {
+            if state.is_empty() {
+                return Err(Error::EmptyState { sample: s, state: t });
+            }
+            if state.len() != width {
+                return Err(Error::StateWidth { sample: s, state: t, expected: width, found: state.len() });
+            }
+            if let Some(&value) = state.iter().find(|&&value| value > max_value) {
+                return Err(Error::StateValue { sample: s, state: t, value, max_value });
+            }
+        }
+    }
+    if samples.iter().all(|sample| sample.len() < 2) {
+        return Err(Error::NoTransitions);
+    }
+    Ok(width)
+}
+
+/// The distributions at the start of each transition, and the values at its end.
+pub type Transitions<P> = (Vec<ComputedDistributions<P>>, Vec<Vec<usize>>);
+
+/// Splits sample traces into transitions, with room for `population_size` computed in each
+/// distribution. The samples must already have been checked by `validate_samples`, and
+/// `population_size` must leave room for the inputs.
+pub fn transitions<P>(samples: &[&[&[usize]]], max_value: usize, population_size: usize) -> Result<Transitions<P>>
+    where P: Probability {
+    let mut computed = Vec::with_capacity(samples.len());
+    let mut targets = Vec::with_capacity(samples.len());
+    for sample in samples.iter() {
+        for values in sample.windows(2) {
+            let mut dists = ComputedDistributions::with_precision(max_value + 1, population_size);
+            dists.set_values(0, values[0])?;
+            computed.push(dists);
+            targets.push(values[1].to_owned());
+        }
+    }
+    Ok((computed, targets))
+}
+
+/// Evaluates a program as a predictor of `output` on the sample traces.
+pub fn evaluate_program(program: &Program, output: usize, samples: &[&[&[usize]]], max_value: usize)
+    -> Result<Evaluation> {
+    let width = validate_samples(samples, max_value)?;
+    if width != program.input_size() {
+        return Err(Error::InputSize { expected: width, found: program.input_size() });
+    }
+    if output >= width {
+        return Err(Error::NoSuchOutput { output, output_size: width });
+    }
+    let (mut computed, targets) = transitions::<f32>(samples, max_value, program.len())?;
+    for dists in computed.iter_mut() {
+        for (i, operator) in program.operators().iter().enumerate().skip(program.input_size()) {
+            operator.run(i, dists)?;
+        }
+    }
+    let root = program.root();
+    let undefined = max_value + 1;
+    let mut confusion = vec![vec![0; undefined + 1]; undefined + 1];
+    let mut predictions = Vec::with_capacity(targets.len());
+    for (dists, target) in computed.iter().zip(targets.iter()) {
+        let (value, probability) = dists.read_likely(root);
+        let target = target[output];
+        confusion[usize::min(target, undefined)][value] += 1;
+        predictions.push(Prediction {
+            value,
+            probability,
+            target,
+        });
+    }
+    let complexity = complexity(program.operators(), root, Complexity::default());
+    Ok(Evaluation {
+        score: compute_score_for_output(&computed, root, output, &targets, complexity),
+        portion_correct: portion_correct_score(root, output, &computed, &targets),
+        log_mse: log_mse_score(root, output, &computed, &targets),
+        complexity: complexity_score(complexity),
+        predictions,
+        confusion,
+    })
+}
+
+/// Evaluates `programs[k]` as a predictor of output `k`, so there may be at most one program for
+/// each value in a state.
+pub fn evaluate_programs(programs: &[Program], samples: &[&[&[usize]]], max_value: usize) -> Result<Vec<Evaluation>> {
+    let width = validate_samples(samples, max_value)?;
+    if programs.len() > width {
+        return Err(Error::NoSuchOutput { output: programs.len() - 1, output_size: width });
+    }
+    programs.iter().enumerate().map(|(k, program)| evaluate_program(program, k, samples, max_value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::operator::Operator::*;
+
+    #[test]
+    fn it_evaluates_programs() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let alternates = Program::new(vec![Initial, Initial, Initial, Not(1)], 3).unwrap();
+        let evaluation = evaluate_program(&alternates, 1, samples, 2).unwrap();
+        assert_eq!(evaluation.portion_correct, 1.0);
+        assert!((evaluation.score - (10.0 + 5.0 * evaluation.log_mse + evaluation.complexity)).abs() < 1e-5);
+        assert_eq!(evaluation.predictions[2], Prediction { value: 1, probability: 1.0, target: 1 });
+        assert_eq!(evaluation.confusion, vec![vec![1, 0, 0, 0], vec![0, 2, 0, 0], vec![0; 4], vec![0; 4]]);
+        let constant = Program::new(vec![Initial, Initial, Initial, Value(2)], 3).unwrap();
+        let evaluations = evaluate_programs(&[constant.clone(), constant], samples, 2).unwrap();
+        assert!((evaluations[0].portion_correct - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(evaluations[0].confusion[0], vec![0, 0, 1, 0]);
+        assert_eq!(evaluations[1].confusion[1], vec![0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn it_reports_outputs_past_the_state() {
+        let samples: &[&[&[usize]]] = &[&[&[1, 0], &[0, 1]]];
+        let program = Program::new(vec![Initial, Initial, Not(0)], 2).unwrap();
+        assert_eq!(evaluate_program(&program, 2, samples, 1), Err(Error::NoSuchOutput { output: 2, output_size: 2 }));
+        let programs = vec![program.clone(), program.clone(), program];
+        assert_eq!(evaluate_programs(&programs, samples, 1), Err(Error::NoSuchOutput { output: 2, output_size: 2 }));
+        assert!(evaluate_programs(&programs[..2], samples, 1).is_ok());
+    }
+}
@@ -1,13 +1,64 @@
+use std::collections::{HashMap};
+use std::collections::hash_map::{DefaultHasher};
+use std::fmt;
+use std::io;
+use std::hash::{Hash, Hasher};
 use rand;
-use rand::{Rng, SeedableRng};
-use super::computed_distributions::{ComputedDistributions};
-use super::operator::{Operator};
-use super::score::{score_values, compute_score_for_output};
+use rand::{SeedableRng};
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::operator::{Categorical, Operator, CATEGORICAL_VALUES};
+use super::program::{Program, reachable};
+use super::dot::{write_dot};
+use super::format::{Formatted, Style};
+use super::evaluate::{transitions, validate_samples};
+use super::error::{Error, Result, Violation};
+use super::exact::{evaluate_exact, independence_error};
+use super::sampling::{sample_distributions};
+use super::fit::{fit_transitions};
+use super::grammar::{Grammar};
+use super::types::{infer, infer_all, new_rand_typed, Candidates, Type};
+use super::score::{score_values, compute_score_for_output, complexity, complexities, propagate_scores, input_dependencies,
+                   Complexity, Credit, InputMask};
 
-pub struct Evolver {
-    computed: Vec<ComputedDistributions>,
+/// How the distributions of new computed are determined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvaluationMode {
+    /// Propagate distributions analytically, assuming that the inputs of each operator are
+    /// independent.
+    Analytic,
+    /// Estimate distributions from `traces` concrete traces per sample.
+    Sampling { traces: usize },
+    /// Use sampling if the domain has more than `max_domain` values, and analytic evaluation
+    /// otherwise.
+    Auto { max_domain: usize, traces: usize },
+    /// Enumerate the joint values of the random ancestors of each computed, falling back to
+    /// analytic evaluation for computed with more than `max_random` random ancestors.
+    Exact { max_random: usize },
+}
+
+/// Whether computed distributions are checked to sum to one after they are evaluated. The worst
+/// drift from one is tracked regardless, and is available from `Evolver::worst_drift`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    /// Don't check distributions, except for a loose bound in debug builds.
+    Unchecked,
+    /// Report an error if any distribution drifts from one by more than `tolerance`.
+    Validate { tolerance: f64 },
+    /// Rescale every distribution to sum to one.
+    Renormalize,
+}
+
+/// Drift beyond this bound indicates a bug rather than rounding error.
+const DEBUG_DRIFT_TOLERANCE: f64 = 1e-3;
+
+/// A population of computed, evolved to predict every output of the sample transitions at once.
+/// The population keeps one row of distributions per transition, with precision `P`; use
+/// `Evolver::<f64>::with_precision` when `f32` rounding would drift past a `Validate` tolerance.
+pub struct Evolver<P = f32> {
+    computed: Vec<ComputedDistributions<P>>,
     operators: Vec<Operator>,
     scores: Vec<f32>,
+    protected: Vec<bool>,
     relocations: Vec<Option<usize>>,
     targets: Vec<Vec<usize>>,
     max_value: usize,
@@ -16,66 +67,444 @@ pub struct Evolver {
     rand_gen: rand::XorShiftRng,
     input_size: usize,
     done_count: usize,
+    /// The distributions of computed `0..evaluated` are up to date with their operators.
+    evaluated: usize,
+    evaluation_mode: EvaluationMode,
+    normalization: Normalization,
+    worst_drift: f64,
+    deduplicate: bool,
+    duplicates_removed: usize,
+    complexity: Complexity,
+    /// The complexity of each computed, as of the last `score`, `prune` or `insert`.
+    complexities: Vec<usize>,
+    credit: Credit,
+    /// The type of each computed, if generation is typed.
+    types: Option<Vec<Type>>,
+    grammar: Option<Grammar>,
+    /// The grammar symbol each computed was generated for, if any.
+    labels: Vec<Option<usize>>,
+    /// The grammar symbol each output's program must derive, if any.
+    starts: Vec<Option<usize>>,
+    /// How generated `Ambiguity` operators choose: uniformly over the domain, or between 0 and 1
+    /// if the domain is larger than a `Categorical` holds.
+    ambiguity: Categorical,
+    /// The inputs each output's program may and must use, if restricted.
+    masks: Vec<Option<InputMask>>,
 }
 
 impl Evolver {
-    pub fn new(samples: &[&[&[usize]]], max_value: usize, population_size: usize) -> Self {
-        let input_size = samples[0][0].len();
-        use std::iter::{repeat};
+    pub fn new(samples: &[&[&[usize]]], max_value: usize, population_size: usize) -> Result<Self> {
+        Evolver::with_precision(samples, max_value, population_size)
+    }
+}
+
+impl<P> Evolver<P> where P: Probability {
+    pub fn with_precision(samples: &[&[&[usize]]], max_value: usize, population_size: usize) -> Result<Self> {
+        let input_size = validate_samples(samples, max_value)?;
+        if population_size <= input_size {
+            return Err(Error::PopulationTooSmall { population_size, input_size });
+        }
         let size = population_size;
-        let mut computed = Vec::with_capacity(samples.len());
-        let mut targets = Vec::with_capacity(samples.len());
-        for sample in samples.iter() {
-            for values in sample.windows(2) {
-                let start = values[0];
-                let end = values[1];
-                let mut dists = ComputedDistributions::new(max_value + 1, population_size);
-                dists.set_values(0, start);
-                computed.push(dists);
-                targets.push(end.to_owned());
-            }
-        }
-        let mut relocations: Vec<Option<usize>> = repeat(None).take(size).collect();
-        for i in 0..input_size {
-            relocations[i] = Some(i);
+        let (computed, targets) = transitions(samples, max_value, population_size)?;
+        let mut relocations: Vec<Option<usize>> = vec![None; size];
+        for (i, relocation) in relocations.iter_mut().enumerate().take(input_size) {
+            *relocation = Some(i);
         }
         let rand_gen = rand::XorShiftRng::from_seed([0xde, 0xad, 0xbe, 0xef]);
-        let operators = repeat(Operator::Initial).take(size).collect();
-        let scores = repeat(0.0).take(size).collect();
-        Evolver {
-            computed: computed,
-            operators: operators,
-            scores: scores,
-            relocations: relocations,
-            targets: targets,
-            max_value: max_value,
-            population_size: population_size,
+        let operators = vec![Operator::Initial; size];
+        let scores = vec![0.0; size];
+        Ok(Evolver {
+            computed,
+            operators,
+            scores,
+            protected: vec![false; size],
+            relocations,
+            targets,
+            max_value,
+            population_size,
             generation: 0,
-            rand_gen: rand_gen,
-            input_size: input_size,
+            rand_gen,
+            input_size,
             done_count: input_size,
-        }
+            evaluated: input_size,
+            evaluation_mode: EvaluationMode::Analytic,
+            normalization: Normalization::Unchecked,
+            worst_drift: 0.0,
+            deduplicate: true,
+            duplicates_removed: 0,
+            complexity: Complexity::default(),
+            complexities: vec![1; size],
+            credit: Credit::default(),
+            types: None,
+            grammar: None,
+            labels: vec![None; size],
+            starts: vec![None; input_size],
+            ambiguity: if max_value < CATEGORICAL_VALUES {
+                Categorical::new(&vec![1.0; max_value + 1]).expect("the domain fits in a categorical")
+            } else {
+                Categorical::COIN
+            },
+            // Each state is both the inputs and the outputs.
+            masks: vec![None; input_size],
+        })
     }
 
     pub fn populate(&mut self) {
+        let mut candidates = match self.types {
+            Some(ref types) if self.grammar.is_none() => Some(Candidates::new(&self.operators, types, self.done_count)),
+            _ => None,
+        };
+        let mut labelled = self.grammar.as_ref().map(|grammar| grammar.labelled(&self.labels[..self.done_count]));
         for i in self.done_count..self.population_size {
-            self.operators[i] = Operator::new_rand(&mut self.rand_gen, i);
+            self.labels[i] = None;
+            if let Some(ref mut labelled) = labelled {
+                self.generate_from_grammar(i, labelled);
+                continue;
+            }
+            match (self.types.as_mut(), candidates.as_mut()) {
+                (Some(types), Some(candidates)) => {
+                    let (operator, t) =
+                        new_rand_typed(&mut self.rand_gen, &self.operators, types, candidates, self.ambiguity, i);
+                    self.operators[i] = operator;
+                    types[i] = t;
+                    candidates.push(operator, t);
+                },
+                _ => self.operators[i] = match Operator::new_rand(&mut self.rand_gen, i) {
+                    Operator::Ambiguity(_) => Operator::Ambiguity(self.ambiguity),
+                    operator => operator,
+                },
+            }
         }
+        self.evaluated = usize::min(self.evaluated, self.done_count);
+        self.debug_check_invariants("populate");
     }
 
-    pub fn evaluate(&mut self) {
-        for i in self.done_count..self.population_size {
-            for dists in self.computed.iter_mut() {
-                self.operators[i].run(i, dists);
+    /// Makes generation typed, so that operators are only given arguments of the types in their
+    /// signatures. The surviving computed must already be well-typed.
+    pub fn set_input_types(&mut self, input_types: &[Type]) -> Result<()> {
+        if input_types.len() != self.input_size {
+            return Err(Error::InputSize { expected: self.input_size, found: input_types.len() });
+        }
+        let mut types = infer_all(&self.operators[..self.done_count], input_types)
+            .map_err(|computed| Error::IllTyped { computed })?;
+        types.resize(self.population_size, Type::Int);
+        self.types = Some(types);
+        Ok(())
+    }
+
+    /// Generates computed `i` from the grammar, as long as it is also well-typed when generation
+    /// is typed, and adds it to the computed `labelled` with its symbol. Falls back to an
+    /// unlabelled constant if neither holds after a few tries.
+    fn generate_from_grammar(&mut self, i: usize, labelled: &mut [Vec<usize>]) {
+        let grammar = self.grammar.as_ref().expect("generating from a grammar");
+        for _ in 0..16 {
+            if let Some((operator, symbol)) = grammar.new_rand(&mut self.rand_gen, labelled, self.ambiguity) {
+                self.operators[i] = operator;
+                if self.types.as_ref().is_none_or(|types| infer(&self.operators, types, i).is_some()) {
+                    self.labels[i] = Some(symbol);
+                    labelled[symbol].push(i);
+                    break;
+                }
             }
         }
+        if self.labels[i].is_none() {
+            self.operators[i] = Operator::Value(0);
+        }
+        if let Some(ref mut types) = self.types {
+            types[i] = infer(&self.operators, types, i).expect("generated operators are well-typed");
+        }
+    }
+
+    /// Generates computed from a grammar instead of the fixed mix of `Operator::new_rand`. Only
+    /// computed generated from the grammar are used as arguments, so survivors from before and
+    /// inserted programs are left out. Fails if the grammar has a different number of inputs, or
+    /// a `Value` outside the domain.
+    pub fn set_grammar(&mut self, grammar: Grammar) -> Result<()> {
+        if grammar.input_size() != self.input_size {
+            return Err(Error::InputSize { expected: self.input_size, found: grammar.input_size() });
+        }
+        if let Some(value) = grammar.max_value().filter(|&value| value > self.max_value) {
+            return Err(Error::ValueOutOfDomain { value, size: self.max_value + 1 });
+        }
+        self.grammar = Some(grammar);
+        for label in self.labels.iter_mut() {
+            *label = None;
+        }
+        for start in self.starts.iter_mut() {
+            *start = None;
+        }
+        Ok(())
+    }
+
+    /// Requires the program for an output to derive a symbol of the grammar, so that only
+    /// computed generated for the symbol, or inputs it derives, are scored and selected as
+    /// predictors of the output.
+    pub fn set_start_symbol(&mut self, output: usize, name: &str) -> Result<()> {
+        let symbol = self.grammar.as_ref().and_then(|grammar| grammar.symbol(name))
+            .ok_or_else(|| Error::NoSuchSymbol { name: name.to_owned() })?;
+        let output_size = self.output_size();
+        *self.starts.get_mut(output).ok_or(Error::NoSuchOutput { output, output_size })? = Some(symbol);
+        Ok(())
+    }
+
+    /// Whether a computed may be a program for an output, given its input mask and start symbol.
+    /// `uses` only needs the inputs each computed depends on if the output has a mask. The output
+    /// must already have been checked by `check_output`.
+    fn admits(&self, output: usize, computed: usize, uses: &[Vec<bool>]) -> bool {
+        let masked = self.masks[output].as_ref().is_some_and(|mask| !mask.admits(&uses[computed]));
+        let underived = match (self.grammar.as_ref(), self.starts[output]) {
+            (Some(grammar), Some(start)) => !grammar.derives(&self.labels, computed, start),
+            _ => false,
+        };
+        !masked && !underived
+    }
+
+    /// The name of the grammar symbol a computed was generated for, if any.
+    pub fn label(&self, computed: usize) -> Result<Option<&str>> {
+        self.check_computed(computed)?;
+        Ok(match (self.grammar.as_ref(), self.labels[computed]) {
+            (Some(grammar), Some(symbol)) => Some(grammar.name(symbol)),
+            _ => None,
+        })
+    }
+
+    /// The type of a computed, if generation is typed.
+    pub fn type_of(&self, computed: usize) -> Result<Option<Type>> {
+        self.check_computed(computed)?;
+        Ok(self.types.as_ref().map(|types| types[computed]))
+    }
+
+    /// Checks that a computed given by the caller has been evaluated, so is neither past the
+    /// population nor left over from before the last `prune`.
+    fn check_output(&self, output: usize) -> Result<()> {
+        let output_size = self.output_size();
+        if output < output_size {
+            Ok(())
+        } else {
+            Err(Error::NoSuchOutput { output, output_size })
+        }
+    }
+
+    fn check_computed(&self, computed: usize) -> Result<()> {
+        if computed < self.evaluated {
+            Ok(())
+        } else {
+            Err(Error::NoSuchComputed { computed, count: self.evaluated })
+        }
+    }
+
+    pub fn set_evaluation_mode(&mut self, mode: EvaluationMode) {
+        self.evaluation_mode = mode;
+    }
+
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    /// The largest amount any distribution evaluated during the last `run_generations` drifted
+    /// from summing to one, before any renormalization.
+    pub fn worst_drift(&self) -> f64 {
+        self.worst_drift
+    }
+
+    /// Evaluates the new computed. Fails if a distribution drifts past the `Validate` tolerance.
+    pub fn evaluate(&mut self) -> Result<()> {
+        let (start, end) = (self.done_count, self.population_size);
+        self.evaluate_range(start, end)?;
+        self.debug_check_invariants("evaluate");
+        Ok(())
+    }
+
+    /// Computes the distributions of computed `start..end`. When sampling, all computed up to
+    /// `end` are re-sampled instead. If normalization fails, the re-computed distributions are
+    /// left unevaluated.
+    fn evaluate_range(&mut self, start: usize, end: usize) -> Result<()> {
+        let domain = self.max_value + 1;
+        let start = match self.evaluation_mode {
+            EvaluationMode::Sampling { traces } => self.evaluate_sampling(end, traces),
+            EvaluationMode::Auto { max_domain, traces } if domain > max_domain => self.evaluate_sampling(end, traces),
+            EvaluationMode::Exact { max_random } => {
+                for dists in self.computed.iter_mut() {
+                    evaluate_exact(&self.operators[..end], self.input_size, start, dists, max_random)
+                        .expect("operators in the population only refer to earlier computed");
+                }
+                start
+            },
+            _ => {
+                for i in start..end {
+                    for dists in self.computed.iter_mut() {
+                        self.operators[i].run(i, dists)
+                            .expect("operators in the population only refer to earlier computed");
+                    }
+                }
+                start
+            },
+        };
+        self.evaluated = start;
+        self.check_normalization(start, end)?;
+        self.evaluated = end;
+        Ok(())
+    }
+
+    fn check_normalization(&mut self, start: usize, end: usize) -> Result<()> {
+        for (s, dists) in self.computed.iter_mut().enumerate() {
+            for i in start..end {
+                let drift = dists.drift(i);
+                self.worst_drift = f64::max(self.worst_drift, drift);
+                match self.normalization {
+                    Normalization::Unchecked => {
+                        debug_assert!(drift <= DEBUG_DRIFT_TOLERANCE,
+                                      "distribution of computed {} in sample {} drifted by {}", i, s, drift);
+                    },
+                    Normalization::Validate { tolerance } if drift > tolerance => {
+                        return Err(Error::Drift { sample: s, computed: i, drift });
+                    },
+                    Normalization::Validate { .. } => {},
+                    Normalization::Renormalize => dists.renormalize(i),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-samples computed up to `end`, returning the first computed whose distributions changed.
+    fn evaluate_sampling(&mut self, end: usize, traces: usize) -> usize {
+        // Every computed is re-sampled, so that all computed in a trace see the same random choices.
+        for (s, dists) in self.computed.iter_mut().enumerate() {
+            let seed = [0xde, s as u32 + 1, self.generation as u32 + 1, 0xef];
+            let mut rand_gen = rand::XorShiftRng::from_seed(seed);
+            sample_distributions(&self.operators[..end], self.input_size, dists, traces, &mut rand_gen);
+        }
+        self.input_size
+    }
+
+    /// Writes the program computing `computed`, or the whole surviving population if it is
+    /// `None`, as a DOT graph labelled with scores, and with the distributions for a sample if
+    /// one is given.
+    pub fn write_dot<W>(&self, out: &mut W, computed: Option<usize>, sample: Option<usize>) -> Result<()>
+        where W: fmt::Write {
+        let nodes = match computed {
+            Some(computed) => {
+                self.check_computed(computed)?;
+                reachable(&self.operators, computed)
+            },
+            None => (0..self.done_count).collect(),
+        };
+        let distributions = match sample {
+            Some(sample) => {
+                let sample_count = self.computed.len();
+                Some(self.computed.get(sample).ok_or(Error::NoSuchSample { sample, sample_count })?)
+            },
+            None => None,
+        };
+        write_dot(out, &self.operators, &nodes, Some(&self.scores), distributions).map_err(|_| Error::Write)
+    }
+
+    /// Extracts the program computing a computed.
+    pub fn extract(&self, computed: usize) -> Result<Program> {
+        self.check_computed(computed)?;
+        Program::extract(&self.operators, self.input_size, computed)
+    }
+
+    /// Adds a program to the population after the surviving computed, and evaluates it. Returns
+    /// the index of the program's root.
+    pub fn insert(&mut self, program: &Program) -> Result<usize> {
+        if program.input_size() != self.input_size {
+            return Err(Error::InputSize { expected: self.input_size, found: program.input_size() });
+        }
+        let start = self.done_count;
+        let end = start + program.len() - self.input_size;
+        if end > self.population_size {
+            return Err(Error::PopulationFull);
+        }
+        let program_types = match self.types {
+            Some(ref types) => Some(infer_all(program.operators(), &types[..self.input_size])
+                .map_err(|computed| Error::IllTyped { computed })?),
+            None => None,
+        };
+        let mut relocations: Vec<Option<usize>> = (0..program.len()).map(|i| Some(start + i - self.input_size)).collect();
+        for (i, relocation) in relocations.iter_mut().enumerate().take(self.input_size) {
+            *relocation = Some(i);
+        }
+        for (i, operator) in program.operators().iter().enumerate().skip(self.input_size) {
+            self.operators[start + i - self.input_size] = operator.relocate(&relocations)?;
+            self.scores[start + i - self.input_size] = 0.0;
+            self.protected[start + i - self.input_size] = false;
+            self.labels[start + i - self.input_size] = None;
+        }
+        for i in start..end {
+            self.complexities[i] = complexity(&self.operators, i, self.complexity);
+        }
+        if let (Some(types), Some(program_types)) = (self.types.as_mut(), program_types) {
+            types[start..end].copy_from_slice(&program_types[self.input_size..]);
+        }
+        self.done_count = end;
+        if let Err(error) = self.evaluate_range(start, end) {
+            self.done_count = start;
+            return Err(error);
+        }
+        self.debug_check_invariants("insert");
+        Ok(relocations[program.root()].expect("every computed of the program is relocated"))
+    }
+
+    /// Adds a known program, given as operators whose result is the last operator, to the
+    /// population. If `protect` is set, the program survives every `prune`, even if it scores
+    /// badly. Returns the index of the program's root.
+    pub fn seed(&mut self, operators: &[Operator], protect: bool) -> Result<usize> {
+        let program = Program::new(operators.to_vec(), self.input_size)?;
+        let start = self.done_count;
+        let root = self.insert(&program)?;
+        if protect {
+            for protected in self.protected[start..self.done_count].iter_mut() {
+                *protected = true;
+            }
+        }
+        Ok(root)
+    }
+
+    /// Whether a computed is part of a protected seed program, and so will survive `prune`.
+    pub fn is_protected(&self, computed: usize) -> Result<bool> {
+        self.check_computed(computed)?;
+        Ok(self.protected[computed])
+    }
+
+    /// How the complexity of programs is measured when scoring. Defaults to
+    /// `Complexity::TreeSize`.
+    pub fn set_complexity(&mut self, complexity: Complexity) {
+        self.complexity = complexity;
+        self.complexities = complexities(&self.operators, complexity);
+    }
+
+    /// How building blocks are credited for the scores of the programs using them. Defaults to
+    /// `Credit::Max`.
+    pub fn set_credit(&mut self, credit: Credit) {
+        self.credit = credit;
+    }
+
+    /// Restricts which inputs the programs for an output may, and must, depend on. Computed the
+    /// mask doesn't admit are neither scored nor selected as predictors of the output, though
+    /// they are still credited as building blocks.
+    pub fn set_input_mask(&mut self, output: usize, mask: InputMask) -> Result<()> {
+        if mask.input_size() != self.input_size {
+            return Err(Error::InputSize { expected: self.input_size, found: mask.input_size() });
+        }
+        let output_size = self.output_size();
+        *self.masks.get_mut(output).ok_or(Error::NoSuchOutput { output, output_size })? = Some(mask);
+        Ok(())
     }
 
     pub fn score(&mut self) {
-        for i in self.input_size..self.population_size {
-            self.scores[i] = 0.0;
-            score_values(&self.computed, i, &self.operators, &mut self.scores, &self.targets);
+        self.complexities = complexities(&self.operators, self.complexity);
+        let uses = input_dependencies(&self.operators, self.input_size);
+        for score in self.scores[..self.input_size].iter_mut() {
+            *score = 0.0;
         }
+        for (i, &complexity) in self.complexities.iter().enumerate().skip(self.input_size) {
+            let admits = |output| self.admits(output, i, &uses);
+            self.scores[i] = score_values(&self.computed, i, complexity, &self.targets, admits).0;
+        }
+        propagate_scores(&self.operators, &mut self.scores, self.credit);
+        self.debug_check_invariants("score");
     }
 
     pub fn prune(&mut self) {
@@ -83,53 +512,361 @@ impl Evolver {
         for i in 0..self.population_size {
             avg_score += self.scores[i] / self.population_size as f32;
         }
+        // Every computed used by a survivor must also survive, which depending on the credit rule
+        // doesn't follow from the scores alone.
+        let mut survives: Vec<bool> = (0..self.population_size)
+            .map(|i| self.scores[i] >= avg_score || self.protected[i])
+            .collect();
+        for i in (self.input_size..self.population_size).rev() {
+            if survives[i] {
+                for x in self.operators[i].dependents().iter().filter_map(|&d| d) {
+                    survives[x] = true;
+                }
+            }
+        }
+        // Surviving computed by fingerprint, by their new index.
+        let mut fingerprints: HashMap<u64, Vec<usize>> = HashMap::new();
+        if self.deduplicate {
+            for i in 0..self.input_size {
+                fingerprints.entry(self.fingerprint(i)).or_default().push(i);
+            }
+        }
+        // Survivors are compacted in place, in a single pass. Each moves to an index no later
+        // than its own, so nothing is overwritten before it has been moved.
+        self.duplicates_removed = 0;
         let mut next_out = self.input_size;
-        for i in self.input_size..self.population_size {
-            if self.scores[i] >= avg_score {
+        for (i, &survives) in survives.iter().enumerate().skip(self.input_size) {
+            if !survives {
+                self.relocations[i] = None;
+                continue;
+            }
+            let operator = self.operators[i].relocate(&self.relocations)
+                .expect("the dependents of a surviving computed also survive");
+            let fingerprint = if self.deduplicate { Some(self.fingerprint(i)) } else { None };
+            // Protected seeds are kept as they are, so are never merged into a duplicate.
+            let merges = fingerprint.filter(|_| !self.protected[i]);
+            let duplicate = merges.and_then(|f| fingerprints.get(&f)).and_then(|candidates| {
+                // Computed of different types or symbols aren't interchangeable, even if they behave the
+                // same.
+                candidates.iter().cloned().find(|&new| {
+                    self.same_distributions(new, i) && self.types.as_ref().is_none_or(|types| types[new] == types[i])
+                        && self.labels[new] == self.labels[i]
+                })
+            });
+            if let Some(kept) = duplicate {
+                // Keep whichever is simpler, as long as it can be moved to the earlier index and
+                // the kept computed isn't part of a protected seed.
+                self.relocations[i] = Some(kept);
+                self.scores[kept] = f32::max(self.scores[kept], self.scores[i]);
+                let fits = operator.dependents().iter().all(|d| d.is_none_or(|x| x < kept));
+                if kept >= self.input_size && fits && !self.protected[kept] {
+                    let old_operator = self.operators[kept];
+                    let old_complexity = complexity(&self.operators, kept, self.complexity);
+                    self.operators[kept] = operator;
+                    if complexity(&self.operators, kept, self.complexity) >= old_complexity {
+                        self.operators[kept] = old_operator;
+                    }
+                }
+                self.duplicates_removed += 1;
+            } else {
                 self.relocations[i] = Some(next_out);
-                self.operators[next_out] = self.operators[i].relocate(&self.relocations);
+                self.operators[next_out] = operator;
                 self.scores[next_out] = self.scores[i];
-
+                self.protected[next_out] = self.protected[i];
+                self.labels[next_out] = self.labels[i];
+                if let Some(ref mut types) = self.types {
+                    types[next_out] = types[i];
+                }
+                for dists in self.computed.iter_mut() {
+                    dists.move_down(i, next_out);
+                }
+                if let Some(fingerprint) = fingerprint {
+                    fingerprints.entry(fingerprint).or_default().push(next_out);
+                }
                 next_out += 1;
-            } else {
-                self.relocations[i] = None;
             }
         }
-        for dists in self.computed.iter_mut() {
-            dists.relocate(&self.relocations);
+        for protected in self.protected[next_out..].iter_mut() {
+            *protected = false;
         }
+        // Survivors may have been replaced by simpler duplicates, so measure them again.
+        let survivors = complexities(&self.operators[..next_out], self.complexity);
+        self.complexities[..next_out].copy_from_slice(&survivors);
         self.done_count = next_out;
+        self.evaluated = next_out;
         self.generation += 1;
+        self.debug_check_invariants("prune");
+    }
+
+    /// Checks the invariants the population relies on, reporting the first one which is broken:
+    ///
+    /// * every per-computed array has an entry for each computed in the population,
+    /// * the inputs are exactly the `Initial` operators among the survivors,
+    /// * operators only refer to earlier operators,
+    /// * when generation is typed, every survivor has the type of its signature,
+    /// * the last `prune` only moved computed to earlier indices, within the survivors, and kept
+    ///   the order of the survivors,
+    /// * every evaluated distribution sums to one, within the tolerance of `Validate`, or a loose
+    ///   bound otherwise.
+    pub fn check_invariants(&self) -> ::std::result::Result<(), Violation> {
+        let expected = self.population_size;
+        let lengths = [("operators", self.operators.len()), ("scores", self.scores.len()),
+                       ("protected", self.protected.len()), ("relocations", self.relocations.len()),
+                       ("labels", self.labels.len()), ("complexities", self.complexities.len())];
+        let counts = self.computed.iter().map(|dists| ("distributions", dists.count()));
+        let types = self.types.iter().map(|types| ("types", types.len()));
+        for (array, found) in lengths.iter().cloned().chain(counts).chain(types) {
+            if found != expected {
+                return Err(Violation::Length { array, expected, found });
+            }
+        }
+        for (i, operator) in self.operators.iter().enumerate() {
+            if i < self.input_size && *operator != Operator::Initial {
+                return Err(Violation::InputNotInitial { computed: i });
+            }
+            if i >= self.input_size && i < self.done_count && *operator == Operator::Initial {
+                return Err(Violation::InitialAfterInputs { computed: i });
+            }
+            if let Some(dependent) = operator.dependents().iter().filter_map(|&d| d).find(|&x| x >= i) {
+                return Err(Violation::ForwardReference { computed: i, dependent });
+            }
+        }
+        if let Some(ref types) = self.types {
+            for i in self.input_size..self.done_count {
+                if infer(&self.operators, types, i) != Some(types[i]) {
+                    return Err(Violation::IllTyped { computed: i });
+                }
+            }
+        }
+        // Survivors keep their order, so each is either moved to the next free index, or merged
+        // into an earlier survivor as a duplicate.
+        let mut next = 0;
+        for (i, relocation) in self.relocations.iter().enumerate() {
+            if let Some(relocation) = *relocation {
+                let moved = if i < self.input_size { relocation != i } else { relocation > i };
+                if moved || relocation >= self.done_count || relocation > next {
+                    return Err(Violation::Relocation { computed: i, relocation });
+                }
+                if relocation == next {
+                    next += 1;
+                }
+            }
+        }
+        let tolerance = match self.normalization {
+            Normalization::Validate { tolerance } => tolerance,
+            _ => DEBUG_DRIFT_TOLERANCE,
+        };
+        for (s, dists) in self.computed.iter().enumerate() {
+            for i in 0..self.evaluated {
+                let drift = dists.drift(i);
+                if drift > tolerance {
+                    return Err(Violation::Drift { sample: s, computed: i, drift });
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn run_generations(&mut self, generations: usize) {
+    fn debug_check_invariants(&self, phase: &str) {
+        if cfg!(debug_assertions) {
+            if let Err(violation) = self.check_invariants() {
+                panic!("broken invariant after {}: {}", phase, violation);
+            }
+        }
+    }
+
+    /// Whether computed which behave identically on every sample are collapsed during `prune`.
+    /// Enabled by default.
+    pub fn set_deduplication(&mut self, deduplicate: bool) {
+        self.deduplicate = deduplicate;
+    }
+
+    /// The number of duplicate computed removed by the last `prune`.
+    pub fn duplicates_removed(&self) -> usize {
+        self.duplicates_removed
+    }
+
+    /// The number of computed which survived the last `prune`, including the inputs.
+    pub fn survivors(&self) -> usize {
+        self.done_count
+    }
+
+    /// The computed distributions of the population, one per sample transition.
+    pub fn distributions(&self) -> &[ComputedDistributions<P>] {
+        &self.computed
+    }
+
+    /// Hashes the distributions of a computed across all samples.
+    fn fingerprint(&self, computed: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for dists in self.computed.iter() {
+            for p in dists.read(computed).iter() {
+                p.to_f64().to_bits().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    fn same_distributions(&self, a: usize, b: usize) -> bool {
+        self.computed.iter().all(|dists| dists.read(a) == dists.read(b))
+    }
+
+    /// Reports the largest total variation distance, across all samples, between the analytic
+    /// and exact distributions of a computed. Returns `None` if the computed has more than
+    /// `max_random` random ancestors.
+    pub fn independence_error(&self, computed: usize, max_random: usize) -> Result<Option<f32>> {
+        self.check_computed(computed)?;
+        let mut worst = 0.0;
+        for dists in self.computed.iter() {
+            match independence_error(&self.operators, self.input_size, computed, dists, max_random) {
+                Some(error) => worst = f32::max(worst, error),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(worst))
+    }
+
+    /// Runs whole generations, stopping at the first evaluation which fails.
+    pub fn run_generations(&mut self, generations: usize) -> Result<()> {
+        self.worst_drift = 0.0;
         for _ in 0..generations {
             self.populate();
-            self.evaluate();
+            self.evaluate()?;
             self.score();
             self.prune();
         }
+        Ok(())
     }
 
     pub fn print_best(&self) {
-        let output_size = self.targets[0].len();
-        for output in 0..output_size {
-            let mut best_score = 0.0;
-            let mut best_computed = 0;
-            for i in self.input_size..self.population_size {
-                let score = compute_score_for_output(&self.computed, i, output, &self.targets, &self.operators);
-                if score > best_score {
-                    best_score = score;
-                    best_computed = i;
-                }
+        self.write_best(&mut io::stdout()).expect("failed to write to stdout");
+    }
+
+    /// The computed which best predicts an output, and its score, or `None` if no computed may be
+    /// a program for the output.
+    pub fn best_computed(&self, output: usize) -> Result<Option<(usize, f32)>> {
+        self.check_output(output)?;
+        let mut best = None;
+        let uses = match self.masks[output] {
+            Some(_) => input_dependencies(&self.operators, self.input_size),
+            None => Vec::new(),
+        };
+        // Only the survivors of the last prune are up to date; the rest of the population is stale.
+        for (i, &complexity) in self.complexities.iter().enumerate().take(self.done_count).skip(self.input_size) {
+            if !self.admits(output, i, &uses) {
+                continue;
+            }
+            let score = compute_score_for_output(&self.computed, i, output, &self.targets, complexity);
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((i, score));
             }
-            println!("best program (scores {}) for {}:", best_score, output);
-            super::operator::pretty_print_program(&self.operators, best_computed);
-            println!("");
+        }
+        Ok(best)
+    }
+
+    /// The number of values in each state, which is also the number of outputs.
+    pub fn output_size(&self) -> usize {
+        self.targets[0].len()
+    }
+
+    /// Extracts the best program for each output, with the distributions of its random choices
+    /// fitted to the transitions. Fails if some output has no program.
+    pub fn best_programs(&self) -> Result<Vec<Program>> {
+        (0..self.output_size())
+            .map(|output| self.best_computed(output)?.ok_or(Error::NoProgram { output })
+                .and_then(|(best, _)| self.extract(best))
+                .and_then(|program| self.fit(&program, output)))
+            .collect()
+    }
+
+    /// Fits the random choices of a program for an output, unless the domain is too large for
+    /// them to be fitted, in which case the program is left as it is.
+    fn fit(&self, program: &Program, output: usize) -> Result<Program> {
+        let size = self.max_value + 1;
+        if size > CATEGORICAL_VALUES {
+            return Ok(program.clone());
+        }
+        let mut computed = Vec::with_capacity(self.computed.len());
+        for dists in self.computed.iter() {
+            let inputs: Vec<usize> = (0..self.input_size).map(|i| dists.read_likely(i).0).collect();
+            let mut transition = ComputedDistributions::with_precision(size, program.len());
+            transition.set_values(0, &inputs)?;
+            computed.push(transition);
+        }
+        fit_transitions(program, output, computed, &self.targets)
+    }
+
+    /// Writes the best program for each output, along with its predictions for each sample.
+    pub fn write_best<W>(&self, out: &mut W) -> io::Result<()> where W: io::Write {
+        for output in 0..self.output_size() {
+            let (best_computed, best_score) = match self.best_computed(output).ok().flatten() {
+                Some(best) => best,
+                None => {
+                    writeln!(out, "no program for {}", output)?;
+                    continue;
+                },
+            };
+            writeln!(out, "best program (scores {}) for {}:", best_score, output)?;
+            writeln!(out, "{}", Formatted::new(&self.operators, best_computed, Style::Infix))?;
             for (dist, target) in self.computed.iter().zip(self.targets.iter()) {
                 let (pred, prob) = dist.read_likely(best_computed);
-                println!("predicted {} with prob {} vs target {}", pred, prob, target[output]);
+                writeln!(out, "predicted {} with prob {} vs target {}", pred, prob, target[output])?;
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::evaluate::{evaluate_program};
+
+    fn evolver() -> Evolver {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = Evolver::new(samples, 2, 32).unwrap();
+        evolver.run_generations(2).unwrap();
+        evolver
+    }
+
+    #[test]
+    fn it_reports_broken_invariants() {
+        assert_eq!(evolver().check_invariants(), Ok(()));
+        assert!(evolver().survivors() > 5);
+        let mut broken = evolver();
+        broken.scores.pop();
+        assert_eq!(broken.check_invariants(), Err(Violation::Length { array: "scores", expected: 32, found: 31 }));
+        let mut broken = evolver();
+        broken.operators[1] = Operator::Value(0);
+        assert_eq!(broken.check_invariants(), Err(Violation::InputNotInitial { computed: 1 }));
+        let mut broken = evolver();
+        broken.operators[3] = Operator::Initial;
+        assert_eq!(broken.check_invariants(), Err(Violation::InitialAfterInputs { computed: 3 }));
+        let mut broken = evolver();
+        broken.operators[4] = Operator::Not(7);
+        assert_eq!(broken.check_invariants(), Err(Violation::ForwardReference { computed: 4, dependent: 7 }));
+        let mut broken = evolver();
+        broken.relocations[5] = Some(6);
+        assert_eq!(broken.check_invariants(), Err(Violation::Relocation { computed: 5, relocation: 6 }));
+        let mut broken = evolver();
+        assert_eq!((broken.relocations[5], broken.relocations[6]), (Some(4), Some(5)));
+        broken.relocations.swap(5, 6);
+        assert_eq!(broken.check_invariants(), Err(Violation::Relocation { computed: 5, relocation: 5 }));
+        let mut broken = evolver();
+        broken.computed[0].store(3, &[0.5, 0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(broken.check_invariants(), Err(Violation::Drift { sample: 0, computed: 3, drift: 0.5 }));
+    }
+
+    #[test]
+    fn it_picks_the_best_among_the_survivors() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let evolver = evolver();
+        for output in 0..evolver.output_size() {
+            let (best, score) = evolver.best_computed(output).unwrap().unwrap();
+            let program = evolver.extract(best).unwrap();
+            assert!(best < evolver.survivors());
+            let evaluation = evaluate_program(&program, output, samples, 2).unwrap();
+            assert!((evaluation.score - score).abs() < 1e-4, "{} scored {} but claimed {}", program, evaluation.score, score);
+        }
     }
 }
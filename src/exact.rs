@@ -0,0 +1,157 @@
+//! Computes distributions exactly, accounting for correlations between computed.
+//!
+//! This is option 4 from `doc/arb.md`. The analytic evaluation in `ComputedDistributions` assumes
+//! that the inputs of each operator are independent, which is false whenever two of them share a
+//! random ancestor. Here, the values of all random ancestors of a computed are enumerated jointly,
+//! and the operators are executed on each assignment. Since this is exponential in the number of
+//! random ancestors, it is only done when there are at most `max_random` of them.
+use rand;
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::error::{Result};
+use super::operator::{Operator};
+use super::program::{reachable};
+
+/// Computes the distributions of computed `start..operators.len()`, exactly where there are at
+/// most `max_random` random ancestors, and analytically otherwise.
+pub fn evaluate_exact<P>(operators: &[Operator], input_size: usize, start: usize, dists: &mut ComputedDistributions<P>,
+                         max_random: usize) -> Result<()> where P: Probability {
+    for i in start..operators.len() {
+        dists.check_bounds(i)?;
+        match exact_distribution(operators, input_size, i, dists, max_random) {
+            Some(distribution) => dists.store(i, &distribution)?,
+            None => operators[i].run(i, dists)?,
+        }
+    }
+    Ok(())
+}
+
+/// Computes the exact distribution of a computed, or `None` if it has more than `max_random`
+/// random ancestors. The distributions of the random ancestors must already be stored in `dists`.
+pub fn exact_distribution<P>(operators: &[Operator], input_size: usize, computed: usize, dists: &ComputedDistributions<P>,
+                             max_random: usize) -> Option<Vec<P>> where P: Probability {
+    let size = dists.size();
+    if operators[computed].dependents().iter().all(|d| d.is_none()) {
+        // Operators without inputs are their own source of randomness.
+        return None;
+    }
+    let ancestors = reachable(operators, computed);
+    let sources: Vec<usize> = ancestors.iter().cloned().filter(|&i| is_random(operators, input_size, i, dists)).collect();
+    if sources.len() > max_random {
+        return None;
+    }
+    let supports: Vec<Vec<usize>> = sources.iter()
+        .map(|&s| (0..(size + 1)).filter(|&v| dists.read(s)[v] > P::zero()).collect())
+        .collect();
+    if supports.iter().any(|s| s.is_empty()) {
+        return None;
+    }
+    let mut distribution = vec![P::zero(); size + 1];
+    let mut values = vec![size; operators.len()];
+    let mut assignment = vec![0; sources.len()];
+    // Random choices are never made below, since every random ancestor has an assigned value.
+    let mut rand_gen = rand::XorShiftRng::new_unseeded();
+    loop {
+        let mut prob = P::one();
+        for (k, &s) in sources.iter().enumerate() {
+            let v = supports[k][assignment[k]];
+            values[s] = v;
+            prob *= dists.read(s)[v];
+        }
+        for &i in ancestors.iter() {
+            if sources.contains(&i) {
+                continue;
+            }
+            if i < input_size {
+                values[i] = dists.read_likely(i).0;
+                continue;
+            }
+            let mut args = [None; 3];
+            for (arg, dep) in args.iter_mut().zip(operators[i].dependents().iter()) {
+                *arg = dep.map(|x| values[x]);
+            }
+            values[i] = operators[i].exec_in_domain(args, size, &mut rand_gen);
+        }
+        distribution[values[computed]] += prob;
+        if !advance(&mut assignment, &supports) {
+            break;
+        }
+    }
+    Some(distribution)
+}
+
+/// Measures how far the analytic distribution of a computed is from its exact distribution, as a
+/// total variation distance between 0 and 1. Returns `None` if the computed has more than
+/// `max_random` random ancestors.
+pub fn independence_error<P>(operators: &[Operator], input_size: usize, computed: usize, dists: &ComputedDistributions<P>,
+                             max_random: usize) -> Option<f32> where P: Probability {
+    let mut analytic = dists.clone();
+    for &i in reachable(operators, computed).iter().filter(|&&i| i >= input_size) {
+        operators[i].run(i, &mut analytic).ok()?;
+    }
+    let exact = match exact_distribution(operators, input_size, computed, &analytic, max_random) {
+        Some(exact) => exact,
+        None if operators[computed].dependents().iter().all(|d| d.is_none()) => analytic.read(computed).to_owned(),
+        None => return None,
+    };
+    let distance: f64 = analytic.read(computed).iter().zip(exact.iter()).map(|(p, q)| (p.to_f64() - q.to_f64()).abs()).sum();
+    Some((distance / 2.0) as f32)
+}
+
+fn is_random<P>(operators: &[Operator], input_size: usize, computed: usize, dists: &ComputedDistributions<P>) -> bool
+    where P: Probability {
+    match operators[computed] {
+        Operator::Ambiguity(_) => true,
+        _ if computed < input_size => dists.read_likely(computed).1 < P::one(),
+        _ => false,
+    }
+}
+
+/// Steps to the next joint assignment, returning false once all have been visited.
+fn advance(assignment: &mut [usize], supports: &[Vec<usize>]) -> bool {
+    for (a, support) in assignment.iter_mut().zip(supports.iter()) {
+        *a += 1;
+        if *a < support.len() {
+            return true;
+        }
+        *a = 0;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::operator::{Categorical};
+
+    #[test]
+    fn it_computes_correlated_distributions() {
+        let operators = [Operator::Initial, Operator::Ambiguity(Categorical::COIN), Operator::Equality(1, 1), Operator::And(1, 2),
+                         Operator::Ite(1, 1, 0)];
+        let mut dists = ComputedDistributions::new(2, operators.len());
+        dists.set_values(0, &[1]).unwrap();
+        evaluate_exact(&operators, 1, 1, &mut dists, 4).unwrap();
+        assert_eq!(dists.read(1), &[0.5, 0.5, 0.0]);
+        assert_eq!(dists.read(2), &[0.0, 1.0, 0.0]);
+        assert_eq!(dists.read(3), &[0.5, 0.5, 0.0]);
+        assert_eq!(dists.read(4), &[0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn it_falls_back_to_analytic_evaluation() {
+        let operators = [Operator::Initial, Operator::Ambiguity(Categorical::COIN), Operator::Equality(1, 1)];
+        let mut dists = ComputedDistributions::new(2, operators.len());
+        dists.set_values(0, &[1]).unwrap();
+        evaluate_exact(&operators, 1, 1, &mut dists, 0).unwrap();
+        assert_eq!(dists.read(2), &[0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn it_measures_independence_error() {
+        let operators = [Operator::Initial, Operator::Ambiguity(Categorical::COIN), Operator::Equality(1, 1), Operator::Not(0)];
+        let mut dists = ComputedDistributions::new(2, operators.len());
+        dists.set_values(0, &[1]).unwrap();
+        assert_eq!(independence_error(&operators, 1, 2, &dists, 4), Some(0.5));
+        assert_eq!(independence_error(&operators, 1, 3, &dists, 4), Some(0.0));
+        assert_eq!(independence_error(&operators, 1, 2, &dists, 0), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Fits the distributions of the `Ambiguity` operators in a program to sample traces.
+//!
+//! Each `Ambiguity` is fitted in turn by expectation maximization, to maximize the likelihood of
+//! the program's output on the transitions where the value of the `Ambiguity` matters. The other
+//! operators are evaluated analytically, as by the `Evolver`.
+use super::computed_distributions::{ComputedDistributions};
+use super::error::{Error, Result};
+use super::evaluate::{transitions, validate_samples};
+use super::operator::{Categorical, Operator, CATEGORICAL_VALUES};
+use super::program::{Program};
+
+/// The most expectation maximization steps taken for each `Ambiguity`.
+const MAX_STEPS: usize = 200;
+
+/// Expectation maximization stops once no probability changes by more than this.
+const CONVERGED: f64 = 1e-6;
+
+/// Fits every `Ambiguity` in a program predicting `output`, returning the program with the fitted
+/// distributions. An `Ambiguity` whose value never affects the likelihood of the output is left
+/// unchanged. Fails if the program has an `Ambiguity` but the domain has more values than a
+/// `Categorical` can hold.
+pub fn fit_ambiguities(program: &Program, output: usize, samples: &[&[&[usize]]], max_value: usize)
+    -> Result<Program> {
+    let width = validate_samples(samples, max_value)?;
+    if width != program.input_size() {
+        return Err(Error::InputSize { expected: width, found: program.input_size() });
+    }
+    let (computed, targets) = transitions::<f64>(samples, max_value, program.len())?;
+    fit_transitions(program, output, computed, &targets)
+}
+
+/// Fits every `Ambiguity` in a program as `fit_ambiguities` does, given the transitions, with the
+/// inputs already set and room for the whole program.
+pub fn fit_transitions(program: &Program, output: usize, mut computed: Vec<ComputedDistributions<f64>>,
+                       targets: &[Vec<usize>]) -> Result<Program> {
+    let output_size = targets.first().map_or(0, |target| target.len());
+    if output >= output_size {
+        return Err(Error::NoSuchOutput { output, output_size });
+    }
+    let mut operators = program.operators().to_vec();
+    let input_size = program.input_size();
+    let root = program.root();
+    let size = computed.first().map_or(0, |dists| dists.size());
+    if size > CATEGORICAL_VALUES && operators.iter().any(|op| matches!(*op, Operator::Ambiguity(_))) {
+        return Err(Error::DomainTooLarge { size, max_size: CATEGORICAL_VALUES });
+    }
+    let run_from = |operators: &[Operator], start: usize, dists: &mut ComputedDistributions<f64>| {
+        operators.iter().enumerate().skip(start).try_for_each(|(i, operator)| operator.run(i, dists))
+    };
+    for dists in computed.iter_mut() {
+        run_from(&operators, input_size, dists)?;
+    }
+    for a in input_size..operators.len() {
+        let current = match operators[a] {
+            Operator::Ambiguity(c) => c,
+            _ => continue,
+        };
+        // The likelihood of each transition's target, given each value of the Ambiguity.
+        let mut likelihoods = vec![vec![0.0; size]; computed.len()];
+        for v in 0..size {
+            operators[a] = Operator::Value(v);
+            for (dists, (target, likelihood)) in computed.iter_mut().zip(targets.iter().zip(likelihoods.iter_mut())) {
+                run_from(&operators, a, dists)?;
+                likelihood[v] = dists.read(root)[usize::min(target[output], size)];
+            }
+        }
+        // Transitions where the value doesn't matter carry no information about the distribution.
+        let informative: Vec<&Vec<f64>> = likelihoods.iter()
+            .filter(|l| l.iter().cloned().fold(0.0, f64::max) - l.iter().cloned().fold(1.0, f64::min) > CONVERGED)
+            .collect();
+        operators[a] = Operator::Ambiguity(if informative.is_empty() {
+            current
+        } else {
+            Categorical::new(&maximize(&informative, size)).unwrap_or(current)
+        });
+        for dists in computed.iter_mut() {
+            run_from(&operators, a, dists)?;
+        }
+    }
+    Program::with_root(operators, input_size, root)
+}
+
+/// Finds the probabilities of `values` values which maximize the likelihood of the transitions, by
+/// expectation maximization from the uniform distribution.
+fn maximize(likelihoods: &[&Vec<f64>], values: usize) -> Vec<f64> {
+    let mut probabilities = vec![1.0 / values as f64; values];
+    for _ in 0..MAX_STEPS {
+        let mut next = vec![0.0; values];
+        for likelihood in likelihoods.iter() {
+            let total: f64 = probabilities.iter().zip(likelihood.iter()).map(|(p, l)| p * l).sum();
+            if total > 0.0 {
+                for (n, (p, l)) in next.iter_mut().zip(probabilities.iter().zip(likelihood.iter())) {
+                    *n += p * l / total;
+                }
+            }
+        }
+        let sum: f64 = next.iter().sum();
+        if sum <= 0.0 {
+            break;
+        }
+        let change = next.iter().zip(probabilities.iter()).map(|(n, p)| (n / sum - p).abs()).fold(0.0, f64::max);
+        probabilities = next.iter().map(|n| n / sum).collect();
+        if change < CONVERGED {
+            break;
+        }
+    }
+    probabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::operator::Operator::*;
+
+    #[test]
+    fn it_fits_biased_choices() {
+        // The next value is 0 four times in seven, 1 twice and 2 once.
+        let samples: &[&[&[usize]]] = &[&[&[0], &[1], &[0], &[2], &[0], &[0], &[0], &[1]]];
+        let program = Program::new(vec![Initial, Ambiguity(Categorical::COIN)], 1).unwrap();
+        let fitted = fit_ambiguities(&program, 0, samples, 2).unwrap();
+        let expected = Categorical::new(&[4.0, 2.0, 1.0]).unwrap();
+        assert_eq!(fitted.operators(), &[Initial, Ambiguity(expected)]);
+        assert_eq!(fitted.display(super::super::format::Style::Infix).to_string(),
+                   "ambiguous(0.5714, 0.2857, 0.1429)");
+    }
+
+    #[test]
+    fn it_only_fits_where_the_choice_is_reached() {
+        // After a 1 the value is always 0, and after a 0 it is 1 three times in four.
+        let samples: &[&[&[usize]]] = &[&[&[1], &[0], &[1], &[0], &[0], &[1], &[0], &[1], &[0]]];
+        let operators = vec![Initial, Value(1), Equality(0, 1), Value(0), Ambiguity(Categorical::COIN), Ite(2, 3, 4)];
+        let program = Program::new(operators, 1).unwrap();
+        let fitted = fit_ambiguities(&program, 0, samples, 1).unwrap();
+        let expected = Categorical::new(&[1.0, 3.0]).unwrap();
+        assert_eq!(fitted.operators()[4], Ambiguity(expected));
+        // The choice is never reached from 1, so is left alone.
+        let unreached: &[&[&[usize]]] = &[&[&[1], &[0]]];
+        assert_eq!(fit_ambiguities(&program, 0, unreached, 1).unwrap(), program);
+    }
+
+    #[test]
+    fn it_rejects_domains_too_large_to_fit() {
+        let samples: &[&[&[usize]]] = &[&[&[0], &[12], &[3]]];
+        let program = Program::new(vec![Initial, Ambiguity(Categorical::COIN)], 1).unwrap();
+        assert_eq!(fit_ambiguities(&program, 0, samples, 12), Err(Error::DomainTooLarge { size: 13, max_size: 12 }));
+        let certain = Program::new(vec![Initial, Value(3)], 1).unwrap();
+        assert_eq!(fit_ambiguities(&certain, 0, samples, 12), Ok(certain));
+    }
+
+    #[test]
+    fn it_keeps_the_root() {
+        let samples: &[&[&[usize]]] = &[&[&[0, 1], &[1, 0], &[0, 1]]];
+        let operators = vec![Initial, Initial, Ambiguity(Categorical::COIN), Not(0), Ite(2, 0, 1)];
+        let program = Program::with_root(operators, 2, 3).unwrap();
+        let fitted = fit_ambiguities(&program, 1, samples, 1).unwrap();
+        assert_eq!(fitted.root(), 3);
+        assert_eq!(fitted.to_string(), "!input[0]");
+    }
+
+    #[test]
+    fn it_reports_outputs_past_the_state() {
+        let samples: &[&[&[usize]]] = &[&[&[0, 1], &[1, 0]]];
+        let program = Program::new(vec![Initial, Initial, Ambiguity(Categorical::COIN)], 2).unwrap();
+        assert_eq!(fit_ambiguities(&program, 2, samples, 1), Err(Error::NoSuchOutput { output: 2, output_size: 2 }));
+    }
+}
@@ -0,0 +1,194 @@
+//! Formats programs as text, writing to any `fmt::Write`.
+//!
+//! Programs are printed as trees, so shared subexpressions are repeated. Use `dot` to see the
+//! structure of the DAG instead.
+use std::fmt::{self, Write};
+use super::operator::{Operator};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Operators as function calls, such as `Ite(Equality(Initial(0), Value(1)), ...)`.
+    Functional,
+    /// Rust-like expressions, such as `if input[0] == 1 { ... } else { ... }`, with only the
+    /// parentheses needed by precedence.
+    Infix,
+    /// S-expressions, such as `(ite (eq (input 0) 1) ... ...)`.
+    SExpression,
+}
+
+/// Displays the program computing `root` in a given style.
+#[derive(Clone, Copy, Debug)]
+pub struct Formatted<'a> {
+    operators: &'a [Operator],
+    root: usize,
+    style: Style,
+}
+
+impl<'a> Formatted<'a> {
+    pub fn new(operators: &'a [Operator], root: usize, style: Style) -> Self {
+        Formatted {
+            operators,
+            root,
+            style,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Formatted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_program(f, self.operators, self.root, self.style)
+    }
+}
+
+/// Writes the program computing `i` in a given style.
+pub fn write_program<W>(out: &mut W, operators: &[Operator], i: usize, style: Style) -> fmt::Result where W: Write {
+    match style {
+        Style::Functional => write_functional(out, operators, i),
+        Style::Infix => write_infix(out, operators, i, 0),
+        Style::SExpression => write_s_expression(out, operators, i),
+    }
+}
+
+fn write_functional<W>(out: &mut W, operators: &[Operator], i: usize) -> fmt::Result where W: Write {
+    match operators[i] {
+        Operator::Initial => write!(out, "Initial({})", i),
+        Operator::Value(v) => write!(out, "Value({})", v),
+        Operator::Ambiguity(c) => write!(out, "Ambiguity({})", c),
+        operator => {
+            write!(out, "{}(", operator.name())?;
+            for (n, x) in operator.dependents().iter().filter_map(|&d| d).enumerate() {
+                if n > 0 {
+                    write!(out, ", ")?;
+                }
+                write_functional(out, operators, x)?;
+            }
+            write!(out, ")")
+        },
+    }
+}
+
+/// How tightly an infix operator binds. Operands with a lower precedence than their position
+/// requires are parenthesized.
+fn precedence(operator: Operator) -> u8 {
+    match operator {
+        Operator::Or(_, _) => 1,
+        Operator::And(_, _) => 2,
+        Operator::Equality(_, _) => 3,
+        Operator::Increment(_) => 4,
+        Operator::Not(_) => 5,
+        _ => 6,
+    }
+}
+
+fn write_infix<W>(out: &mut W, operators: &[Operator], i: usize, min_precedence: u8) -> fmt::Result
+    where W: Write {
+    let operator = operators[i];
+    let parenthesize = precedence(operator) < min_precedence;
+    if parenthesize {
+        write!(out, "(")?;
+    }
+    match operator {
+        Operator::Initial => write!(out, "input[{}]", i)?,
+        Operator::Value(v) => write!(out, "{}", v)?,
+        Operator::Ambiguity(c) => write!(out, "ambiguous({})", c)?,
+        // Or and And are left associative, Equality is not associative.
+        Operator::Or(x, y) => write_binary(out, operators, x, " || ", y, (1, 2))?,
+        Operator::And(x, y) => write_binary(out, operators, x, " && ", y, (2, 3))?,
+        Operator::Equality(x, y) => write_binary(out, operators, x, " == ", y, (4, 4))?,
+        Operator::Increment(x) => {
+            write!(out, "1 + ")?;
+            write_infix(out, operators, x, 4)?;
+        },
+        Operator::Not(x) => {
+            write!(out, "!")?;
+            write_infix(out, operators, x, 5)?;
+        },
+        Operator::Ite(x, y, z) => {
+            write!(out, "if ")?;
+            write_infix(out, operators, x, 0)?;
+            write!(out, " {{ ")?;
+            write_infix(out, operators, y, 0)?;
+            write!(out, " }} else {{ ")?;
+            write_infix(out, operators, z, 0)?;
+            write!(out, " }}")?;
+        },
+    }
+    if parenthesize {
+        write!(out, ")")?;
+    }
+    Ok(())
+}
+
+fn write_binary<W>(out: &mut W, operators: &[Operator], x: usize, symbol: &str, y: usize, min_precedences: (u8, u8))
+    -> fmt::Result where W: Write {
+    write_infix(out, operators, x, min_precedences.0)?;
+    write!(out, "{}", symbol)?;
+    write_infix(out, operators, y, min_precedences.1)
+}
+
+/// The head of the S-expression for an operator with dependents.
+fn s_expression_head(operator: Operator) -> &'static str {
+    match operator {
+        Operator::Equality(_, _) => "eq",
+        Operator::Increment(_) => "inc",
+        Operator::And(_, _) => "and",
+        Operator::Or(_, _) => "or",
+        Operator::Not(_) => "not",
+        Operator::Ite(_, _, _) => "ite",
+        _ => panic!("{:?} has no dependents", operator),
+    }
+}
+
+fn write_s_expression<W>(out: &mut W, operators: &[Operator], i: usize) -> fmt::Result where W: Write {
+    match operators[i] {
+        Operator::Initial => write!(out, "(input {})", i),
+        Operator::Value(v) => write!(out, "{}", v),
+        Operator::Ambiguity(c) => {
+            write!(out, "(ambiguous")?;
+            for p in c.probabilities().iter() {
+                write!(out, " {:?}", p)?;
+            }
+            write!(out, ")")
+        },
+        operator => {
+            write!(out, "({}", s_expression_head(operator))?;
+            for x in operator.dependents().iter().filter_map(|&d| d) {
+                write!(out, " ")?;
+                write_s_expression(out, operators, x)?;
+            }
+            write!(out, ")")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::operator::Operator::*;
+
+    fn format(operators: &[Operator], style: Style) -> String {
+        Formatted::new(operators, operators.len() - 1, style).to_string()
+    }
+
+    #[test]
+    fn it_formats_all_styles() {
+        let operators = [Initial, Initial, Value(2), Equality(1, 2), Not(0), Ite(3, 4, 0)];
+        assert_eq!(format(&operators, Style::Functional),
+                   "Ite(Equality(Initial(1), Value(2)), Not(Initial(0)), Initial(0))");
+        assert_eq!(format(&operators, Style::Infix), "if input[1] == 2 { !input[0] } else { input[0] }");
+        assert_eq!(format(&operators, Style::SExpression), "(ite (eq (input 1) 2) (not (input 0)) (input 0))");
+    }
+
+    #[test]
+    fn it_minimizes_parentheses() {
+        assert_eq!(format(&[Initial, Initial, And(0, 1), Or(2, 0)], Style::Infix), "input[0] && input[1] || input[0]");
+        assert_eq!(format(&[Initial, Initial, Or(0, 1), And(2, 0)], Style::Infix), "(input[0] || input[1]) && input[0]");
+        assert_eq!(format(&[Initial, Initial, And(0, 1), And(2, 0)], Style::Infix), "input[0] && input[1] && input[0]");
+        assert_eq!(format(&[Initial, Initial, And(0, 1), And(0, 2)], Style::Infix), "input[0] && (input[0] && input[1])");
+        assert_eq!(format(&[Initial, Initial, Equality(0, 1), Equality(2, 0)], Style::Infix),
+                   "(input[0] == input[1]) == input[0]");
+        assert_eq!(format(&[Initial, Increment(0), Increment(1), Equality(2, 0)], Style::Infix),
+                   "1 + 1 + input[0] == input[0]");
+        assert_eq!(format(&[Initial, Increment(0), Not(1), Not(2)], Style::Infix), "!!(1 + input[0])");
+    }
+}
@@ -0,0 +1,334 @@
+//! User-supplied grammars which constrain the shape of generated programs.
+//!
+//! A grammar has one rule per line, giving the alternatives for a symbol:
+//!
+//! ```text
+//! # The winner is an if-then-else chain over equality tests.
+//! winner ::= Ite(test, winner, winner) | Value(0) | Value(1) | Value(2)
+//! test ::= Equality(value, value)
+//! value ::= input | Value(0) | Value(1) | Value(2) | Increment(value)
+//! ```
+//!
+//! Alternatives are `input` for any input, `input[k]` for input `k`, `Value(n)`, `Ambiguity`, or
+//! an operator's name with one symbol per argument. Each generated computed is labelled with the
+//! symbol it was generated for, and is only used as an argument where that symbol is expected.
+//! Likewise, `Evolver::set_start_symbol` restricts the programs for an output to those derived
+//! from a symbol.
+use std::error;
+use std::fmt;
+use rand;
+use super::operator::{Categorical, Operator};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GrammarError {
+    /// The line, starting from 1, where the error was found.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for GrammarError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Alternative {
+    /// Any input if `None`, or the given input.
+    Input(Option<usize>),
+    Value(usize),
+    Ambiguity,
+    /// An operator, with the symbol of each argument.
+    Operator(OperatorKind, Vec<usize>),
+}
+
+/// The operators which can appear in a grammar with arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperatorKind {
+    Equality,
+    Increment,
+    And,
+    Or,
+    Not,
+    Ite,
+}
+
+impl OperatorKind {
+    /// The name of the operator's variant, as written in a grammar.
+    pub fn name(self) -> &'static str {
+        match self {
+            OperatorKind::Equality => "Equality",
+            OperatorKind::Increment => "Increment",
+            OperatorKind::And => "And",
+            OperatorKind::Or => "Or",
+            OperatorKind::Not => "Not",
+            OperatorKind::Ite => "Ite",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            OperatorKind::Increment | OperatorKind::Not => 1,
+            OperatorKind::Equality | OperatorKind::And | OperatorKind::Or => 2,
+            OperatorKind::Ite => 3,
+        }
+    }
+
+    /// Builds the operator, using the first `arity()` of `args`.
+    pub fn build(self, args: [usize; 3]) -> Operator {
+        match self {
+            OperatorKind::Equality => Operator::Equality(args[0], args[1]),
+            OperatorKind::Increment => Operator::Increment(args[0]),
+            OperatorKind::And => Operator::And(args[0], args[1]),
+            OperatorKind::Or => Operator::Or(args[0], args[1]),
+            OperatorKind::Not => Operator::Not(args[0]),
+            OperatorKind::Ite => Operator::Ite(args[0], args[1], args[2]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grammar {
+    input_size: usize,
+    names: Vec<String>,
+    rules: Vec<Vec<Alternative>>,
+    /// The inputs each symbol derives.
+    inputs: Vec<Vec<usize>>,
+}
+
+const OPERATORS: [OperatorKind; 6] = [OperatorKind::Equality, OperatorKind::Increment, OperatorKind::And,
+                                      OperatorKind::Or, OperatorKind::Not, OperatorKind::Ite];
+
+impl Grammar {
+    /// Parses a grammar for programs with `input_size` inputs. Blank lines and everything after
+    /// a `#` are ignored, and a symbol may have several rules.
+    pub fn parse(text: &str, input_size: usize) -> Result<Grammar, GrammarError> {
+        let lines: Vec<(usize, &str)> = text.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .enumerate()
+            .map(|(n, line)| (n + 1, line))
+            .filter(|&(_, line)| !line.is_empty())
+            .collect();
+        let mut rules: Vec<(usize, &str, &str)> = Vec::with_capacity(lines.len());
+        let mut names: Vec<String> = Vec::new();
+        for &(line, text) in lines.iter() {
+            let mut sides = text.splitn(2, "::=").map(|side| side.trim());
+            let (name, alternatives) = match (sides.next(), sides.next()) {
+                (Some(name), Some(alternatives)) if is_identifier(name) => (name, alternatives),
+                _ => return Err(GrammarError { line, message: "expected `symbol ::= alternatives`".to_owned() }),
+            };
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_owned());
+            }
+            rules.push((line, name, alternatives));
+        }
+        if names.is_empty() {
+            return Err(GrammarError { line: 1, message: "grammar has no rules".to_owned() });
+        }
+        let mut grammar = Grammar { input_size, rules: vec![Vec::new(); names.len()], inputs: Vec::new(), names };
+        for (line, name, alternatives) in rules {
+            let symbol = grammar.symbol(name).expect("every rule's symbol is named");
+            for alternative in alternatives.split('|') {
+                let alternative = grammar.alternative(alternative.trim())
+                    .map_err(|message| GrammarError { line, message })?;
+                grammar.rules[symbol].push(alternative);
+            }
+        }
+        grammar.inputs = grammar.rules.iter()
+            .map(|rule| (0..input_size)
+                .filter(|&x| rule.iter().any(|a| matches!(*a, Alternative::Input(k) if k.is_none_or(|k| k == x))))
+                .collect())
+            .collect();
+        Ok(grammar)
+    }
+
+    fn alternative(&self, text: &str) -> Result<Alternative, String> {
+        let (name, args) = match text.find('(') {
+            Some(open) if text.ends_with(')') => {
+                let args: Vec<&str> = text[open + 1..text.len() - 1].split(',').map(|arg| arg.trim()).collect();
+                (text[..open].trim(), args)
+            },
+            _ => (text, Vec::new()),
+        };
+        let number = |arg: &str| arg.parse::<usize>().map_err(|_| format!("expected a number, found `{}`", arg));
+        match (name, args.as_slice()) {
+            ("input", []) => Ok(Alternative::Input(None)),
+            ("Value", [value]) => Ok(Alternative::Value(number(value)?)),
+            ("Ambiguity", []) => Ok(Alternative::Ambiguity),
+            _ if name.starts_with("input[") && name.ends_with(']') && args.is_empty() => {
+                let input = number(&name[6..name.len() - 1])?;
+                if input >= self.input_size {
+                    return Err(format!("input {} does not exist", input));
+                }
+                Ok(Alternative::Input(Some(input)))
+            },
+            _ => {
+                let &kind = OPERATORS.iter().find(|kind| kind.name() == name)
+                    .ok_or_else(|| format!("unknown alternative `{}`", text))?;
+                if args.len() != kind.arity() {
+                    return Err(format!("{} takes {} arguments, found {}", name, kind.arity(), args.len()));
+                }
+                let symbols = args.iter()
+                    .map(|&arg| self.symbol(arg).ok_or_else(|| format!("undefined symbol `{}`", arg)))
+                    .collect::<Result<Vec<usize>, String>>()?;
+                Ok(Alternative::Operator(kind, symbols))
+            },
+        }
+    }
+
+    /// The number of inputs of the programs the grammar generates.
+    pub fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    /// Finds a symbol by name.
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    pub fn name(&self, symbol: usize) -> &str {
+        &self.names[symbol]
+    }
+
+    pub fn alternatives(&self, symbol: usize) -> &[Alternative] {
+        &self.rules[symbol]
+    }
+
+    /// The largest value of any `Value` alternative, if there is one.
+    pub fn max_value(&self) -> Option<usize> {
+        self.rules.iter().flatten()
+            .filter_map(|alternative| match *alternative {
+                Alternative::Value(v) => Some(v),
+                _ => None,
+            })
+            .max()
+    }
+
+    /// Whether computed `x`, labelled with the symbols in `labels`, can be used where `symbol` is
+    /// expected. Inputs are unlabelled, and match the `input` alternatives of the symbol.
+    pub fn derives(&self, labels: &[Option<usize>], x: usize, symbol: usize) -> bool {
+        if x < self.input_size {
+            self.inputs[symbol].contains(&x)
+        } else {
+            labels[x] == Some(symbol)
+        }
+    }
+
+    /// Lists the computed labelled with each symbol, in order, for `new_rand`.
+    pub fn labelled(&self, labels: &[Option<usize>]) -> Vec<Vec<usize>> {
+        let mut labelled = vec![Vec::new(); self.names.len()];
+        for (x, label) in labels.iter().enumerate() {
+            if let Some(symbol) = *label {
+                labelled[symbol].push(x);
+            }
+        }
+        labelled
+    }
+
+    /// Generates a random operator by choosing a symbol and one of its alternatives, with
+    /// arguments chosen among the computed `labelled` with the symbols the alternative expects,
+    /// and the inputs those symbols derive. `Ambiguity` alternatives choose by `ambiguity`.
+    /// Returns the operator and its symbol, or `None` if no alternative could be built from the
+    /// computed so far.
+    pub fn new_rand<R>(&self, rand_gen: &mut R, labelled: &[Vec<usize>], ambiguity: Categorical)
+        -> Option<(Operator, usize)> where R: rand::Rng {
+        // Inputs are never generated, and arguments may not exist yet, so try a few times.
+        for _ in 0..16 {
+            let symbol = rand_gen.next_u32() as usize % self.names.len();
+            let alternatives = &self.rules[symbol];
+            let operator = match alternatives[rand_gen.next_u32() as usize % alternatives.len()] {
+                Alternative::Input(_) => None,
+                Alternative::Value(v) => Some(Operator::Value(v)),
+                Alternative::Ambiguity => Some(Operator::Ambiguity(ambiguity)),
+                Alternative::Operator(kind, ref symbols) => {
+                    let mut args = [0; 3];
+                    let mut complete = true;
+                    for (arg, &expected) in args.iter_mut().zip(symbols.iter()) {
+                        let (inputs, computed) = (&self.inputs[expected], &labelled[expected]);
+                        let count = inputs.len() + computed.len();
+                        if count == 0 {
+                            complete = false;
+                            break;
+                        }
+                        let n = rand_gen.next_u32() as usize % count;
+                        *arg = if n < inputs.len() { inputs[n] } else { computed[n - inputs.len()] };
+                    }
+                    if complete { Some(kind.build(args)) } else { None }
+                },
+            };
+            if let Some(operator) = operator {
+                return Some((operator, symbol));
+            }
+        }
+        None
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, XorShiftRng};
+    use super::*;
+
+    const WINNER: &str = "# The winner is an if-then-else chain over equality tests.
+winner ::= Ite(test, winner, winner) | Value(0) | Value(1) | Value(2)
+test ::= Equality(value, value)
+value ::= input | Value(0) | Value(1) | Value(2)
+value ::= Increment(value)  # counters
+";
+
+    #[test]
+    fn it_parses_grammars() {
+        let grammar = Grammar::parse(WINNER, 3).unwrap();
+        assert_eq!(grammar.symbol("value"), Some(2));
+        assert_eq!(grammar.alternatives(1), &[Alternative::Operator(OperatorKind::Equality, vec![2, 2])]);
+        assert_eq!(grammar.max_value(), Some(2));
+        assert_eq!(grammar.alternatives(2).len(), 5);
+        assert_eq!(Grammar::parse("a ::= Not(b)", 1),
+                   Err(GrammarError { line: 1, message: "undefined symbol `b`".to_owned() }));
+        assert_eq!(Grammar::parse("\na ::= input[1]", 1),
+                   Err(GrammarError { line: 2, message: "input 1 does not exist".to_owned() }));
+        assert_eq!(Grammar::parse("a ::= And(a)", 1),
+                   Err(GrammarError { line: 1, message: "And takes 2 arguments, found 1".to_owned() }));
+        assert!(Grammar::parse("a = Value(0)", 1).is_err());
+        assert!(Grammar::parse("# nothing\n", 1).is_err());
+    }
+
+    #[test]
+    fn it_generates_from_the_grammar() {
+        let grammar = Grammar::parse(WINNER, 3).unwrap();
+        let mut rand_gen = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut operators = vec![Operator::Initial; 3];
+        let mut labels = vec![None; 3];
+        let mut labelled = grammar.labelled(&labels);
+        for i in 3..300 {
+            let (operator, symbol) = grammar.new_rand(&mut rand_gen, &labelled, Categorical::COIN).unwrap();
+            operators.push(operator);
+            labels.push(Some(symbol));
+            labelled[symbol].push(i);
+        }
+        assert_eq!(grammar.labelled(&labels), labelled);
+        let (winner, test, value) = (0, 1, 2);
+        for (i, operator) in operators.iter().enumerate().skip(3) {
+            match *operator {
+                Operator::Ite(c, a, b) => {
+                    assert_eq!(labels[i], Some(winner));
+                    assert_eq!((labels[c], labels[a], labels[b]), (Some(test), Some(winner), Some(winner)));
+                },
+                Operator::Equality(x, y) => {
+                    assert_eq!(labels[i], Some(test));
+                    assert!(grammar.derives(&labels, x, value) && grammar.derives(&labels, y, value));
+                },
+                Operator::Increment(x) => assert!(grammar.derives(&labels, x, value)),
+                Operator::Value(_) => assert!(labels[i] != Some(test)),
+                _ => panic!("{:?} is not in the grammar", operator),
+            }
+        }
+        assert!(operators.iter().any(|op| matches!(*op, Operator::Ite(_, _, _))));
+    }
+}
@@ -4,10 +4,24 @@
 
 extern crate rand;
 
-mod computed_distributions;
-mod operator;
-mod score;
-mod evolver;
+pub mod error;
+pub mod computed_distributions;
+pub mod operator;
+pub mod score;
+pub mod evolver;
+pub mod program;
+pub mod simplify;
+pub mod codegen;
+pub mod dot;
+pub mod format;
+pub mod parse;
+mod sampling;
+mod exact;
+pub mod evaluate;
+pub mod types;
+pub mod grammar;
+pub mod fit;
+
 /// Finds transition functions for all variables.
 ///
 /// The evolve algorithm works like this:
@@ -20,10 +34,11 @@ mod evolver;
 ///  * Prune the operator array, using some grading function. All operators that pass have new indices recorded in the update array.
 ///  * Move all traces and operators based on the update array.
 ///  * Check if done.
-pub fn evolve(samples: &[&[&[usize]]], max_value: usize, size: usize, max_generations: usize) {
-    let mut evolver = evolver::Evolver::new(samples, max_value, size);
-    evolver.run_generations(max_generations);
+pub fn evolve(samples: &[&[&[usize]]], max_value: usize, size: usize, max_generations: usize) -> error::Result<()> {
+    let mut evolver = evolver::Evolver::new(samples, max_value, size)?;
+    evolver.run_generations(max_generations)?;
     evolver.print_best();
+    Ok(())
 }
 
 #[cfg(test)]
@@ -33,9 +48,9 @@ mod tests {
     #[test]
     fn it_computes_probabilities() {
         let mut distributions = super::computed_distributions::ComputedDistributions::new(2, 4);
-        distributions.store(0, &[0.5, 0.5, 0.0]);
-        distributions.store(1, &[1.0, 0.0, 0.0]);
-        distributions.store(2, &[0.0, 1.0, 0.0]);
+        distributions.store(0, &[0.5, 0.5, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 1.0, 0.0]).unwrap();
         distributions.compute_at_3(3, (0, 1, 2), |x, y, z| {
             if x != 0 {
                 y
@@ -49,28 +64,400 @@ mod tests {
     #[test]
     fn it_computes_ite() {
         let mut distributions = super::computed_distributions::ComputedDistributions::new(2, 4);
-        distributions.store(0, &[0.5, 0.5, 0.0]);
-        distributions.store(1, &[1.0, 0.0, 0.0]);
-        distributions.store(2, &[0.0, 1.0, 0.0]);
-        super::operator::Operator::Ite(0, 1, 2).run(3, &mut distributions);
+        distributions.store(0, &[0.5, 0.5, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 1.0, 0.0]).unwrap();
+        super::operator::Operator::Ite(0, 1, 2).run(3, &mut distributions).unwrap();
         assert_eq!(distributions.read(3), &[0.5, 0.5, 0.0]);
     }
 
     #[test]
     fn it_computes_not() {
         let mut distributions = super::computed_distributions::ComputedDistributions::new(2, 4);
-        distributions.store(0, &[0.5, 0.5, 0.0]);
-        distributions.store(1, &[1.0, 0.0, 0.0]);
-        distributions.store(2, &[0.0, 1.0, 0.0]);
-        super::operator::Operator::Not(0).run(3, &mut distributions);
+        distributions.store(0, &[0.5, 0.5, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 1.0, 0.0]).unwrap();
+        super::operator::Operator::Not(0).run(3, &mut distributions).unwrap();
         assert_eq!(distributions.read(3), &[0.5, 0.5, 0.0]);
-        super::operator::Operator::Not(1).run(3, &mut distributions);
+        super::operator::Operator::Not(1).run(3, &mut distributions).unwrap();
         assert_eq!(distributions.read(3), &[0.0, 1.0, 0.0]);
-        super::operator::Operator::Not(2).run(3, &mut distributions);
+        super::operator::Operator::Not(2).run(3, &mut distributions).unwrap();
         assert_eq!(distributions.read(3), &[1.0, 0.0, 0.0]);
     }
 
+    #[test]
+    fn it_ignores_undefined_untaken_branches() {
+        let mut distributions = super::computed_distributions::ComputedDistributions::new(2, 4);
+        distributions.store(0, &[0.0, 1.0, 0.0]).unwrap();
+        distributions.store(1, &[1.0, 0.0, 0.0]).unwrap();
+        distributions.store(2, &[0.0, 0.0, 1.0]).unwrap();
+        super::operator::Operator::Ite(0, 1, 2).run(3, &mut distributions).unwrap();
+        assert_eq!(distributions.read(3), &[1.0, 0.0, 0.0]);
+        super::operator::Operator::Ite(0, 2, 1).run(3, &mut distributions).unwrap();
+        assert_eq!(distributions.read(3), &[0.0, 0.0, 1.0]);
+        super::operator::Operator::Ite(2, 0, 1).run(3, &mut distributions).unwrap();
+        assert_eq!(distributions.read(3), &[0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn it_keeps_undefined_ambiguity_in_small_domains() {
+        let mut distributions = super::computed_distributions::ComputedDistributions::new(1, 1);
+        super::operator::Operator::Ambiguity(super::operator::Categorical::COIN).run(0, &mut distributions).unwrap();
+        assert_eq!(distributions.read(0), &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn it_computes_categorical_ambiguity() {
+        use operator::Categorical;
+        let thirds = Categorical::new(&[1.0, 1.0, 1.0]).unwrap();
+        assert_eq!(thirds.to_string(), "0.3334, 0.3333, 0.3333");
+        assert_eq!(Categorical::new(&[0.3334, 0.3333, 0.3333]), Some(thirds));
+        assert_eq!(Categorical::new(&[0.0, 0.0]), None);
+        assert_eq!(Categorical::new(&[0.5, -0.5, 1.0]), None);
+        let mut distributions = super::computed_distributions::ComputedDistributions::new(2, 1);
+        let biased = Categorical::new(&[0.25, 0.25, 0.5]).unwrap();
+        super::operator::Operator::Ambiguity(biased).run(0, &mut distributions).unwrap();
+        // Values outside of the domain are undefined.
+        assert_eq!(distributions.read(0), &[0.25, 0.25, 0.5]);
+    }
+
+    #[test]
+    fn it_generates_and_fits_choices_over_the_domain() {
+        use operator::{Categorical, Operator};
+        // The next value is 0 four times in seven, 1 twice and 2 once.
+        let samples: &[&[&[usize]]] = &[&[&[0], &[1], &[0], &[2], &[0], &[0], &[0], &[1]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 512).unwrap();
+        evolver.populate();
+        evolver.evaluate().unwrap();
+        let thirds = Operator::Ambiguity(Categorical::new(&[1.0, 1.0, 1.0]).unwrap());
+        let choices: Vec<Operator> = (1..512)
+            .map(|i| *evolver.extract(i).unwrap().operators().last().unwrap())
+            .filter(|operator| matches!(*operator, Operator::Ambiguity(_)))
+            .collect();
+        assert!(!choices.is_empty() && choices.iter().all(|&choice| choice == thirds), "{:?}", choices);
+        // The best program is the only one, and is fitted to the samples.
+        let mut evolver = evolver::Evolver::new(samples, 2, 8).unwrap();
+        evolver.seed(&[Operator::Initial, Operator::Ambiguity(Categorical::COIN)], false).unwrap();
+        let fitted = Operator::Ambiguity(Categorical::new(&[4.0, 2.0, 1.0]).unwrap());
+        assert_eq!(evolver.best_programs().unwrap()[0].operators(), &[Operator::Initial, fitted]);
+    }
+
+    #[test]
+    fn it_preserves_mass_in_all_operators() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        let mut rand_gen = XorShiftRng::from_seed([4, 3, 2, 1]);
+        for _ in 0..100 {
+            let size = 1 + rand_gen.gen_range(0, 4);
+            let input_size = 3;
+            let count = 12;
+            let mut operators = vec![super::operator::Operator::Initial; input_size];
+            for i in input_size..count {
+                operators.push(super::operator::Operator::new_rand(&mut rand_gen, i));
+            }
+            let mut distributions = super::computed_distributions::ComputedDistributions::new(size, count);
+            for i in 0..input_size {
+                let mut distribution: Vec<f32> = (0..(size + 1)).map(|_| rand_gen.gen::<f32>()).collect();
+                let total: f32 = distribution.iter().sum();
+                for p in distribution.iter_mut() {
+                    *p /= total;
+                }
+                distributions.store(i, &distribution).unwrap();
+            }
+            let mut exact = distributions.clone();
+            let mut sampled = distributions.clone();
+            for (i, operator) in operators.iter().enumerate().skip(input_size) {
+                operator.run(i, &mut distributions).unwrap();
+            }
+            super::exact::evaluate_exact(&operators, input_size, input_size, &mut exact, 2).unwrap();
+            super::sampling::sample_distributions(&operators, input_size, &mut sampled, 10, &mut rand_gen);
+            for dists in &[distributions, exact, sampled] {
+                for (i, operator) in operators.iter().enumerate() {
+                    let total: f32 = dists.read(i).iter().sum();
+                    assert!((total - 1.0).abs() < 1e-5, "{:?} at {} sums to {}", operator, i, total);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_evolves_with_sampling() {
+        use operator::Operator::*;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 32).unwrap();
+        // The domain has 3 values, so sampling is used.
+        evolver.set_evaluation_mode(evolver::EvaluationMode::Auto { max_domain: 2, traces: 32 });
+        let alternates = evolver.seed(&[Initial, Initial, Initial, Not(1)], true).unwrap();
+        let coin = evolver.seed(&[Initial, Initial, Initial, Ambiguity(operator::Categorical::COIN)], true).unwrap();
+        for (dists, state) in evolver.distributions().iter().zip(samples[0].iter()) {
+            // Deterministic programs are sampled exactly.
+            assert_eq!(dists.read_likely(alternates), (1 - state[1], 1.0));
+            // Random ones are estimated from the traces.
+            let coin = dists.read(coin);
+            assert!(coin.iter().all(|p| (p * 32.0).fract() == 0.0));
+            assert!(coin[0] > 0.0 && coin[1] > 0.0 && coin[0] + coin[1] == 1.0);
+        }
+        evolver.run_generations(8).unwrap();
+        assert!(evolver.survivors() > 4);
+        // Not(player) always predicts the next player.
+        assert!(evolver.best_computed(1).unwrap().is_some_and(|(_, score)| score >= 10.0));
+    }
+
+    #[test]
+    fn it_evolves_exactly() {
+        use operator::Operator::*;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let coin = Ambiguity(operator::Categorical::COIN);
+        let independent = [Initial, Initial, Initial, coin, coin, And(3, 4)];
+        // Both arguments of the Equality are the same random choice, so it is always 1.
+        let correlated = [Initial, Initial, Initial, coin, Equality(3, 3)];
+        let mut exact = evolver::Evolver::new(samples, 2, 32).unwrap();
+        exact.set_evaluation_mode(evolver::EvaluationMode::Exact { max_random: 3 });
+        let mut analytic = evolver::Evolver::new(samples, 2, 32).unwrap();
+        let i = exact.seed(&independent, true).unwrap();
+        let c = exact.seed(&correlated, true).unwrap();
+        assert_eq!((analytic.seed(&independent, true), analytic.seed(&correlated, true)), (Ok(i), Ok(c)));
+        for (e, a) in exact.distributions().iter().zip(analytic.distributions().iter()) {
+            assert_eq!(e.read(i), &[0.75, 0.25, 0.0, 0.0]);
+            assert_eq!(e.read(i), a.read(i));
+            assert_eq!(e.read(c), &[0.0, 1.0, 0.0, 0.0]);
+            assert_eq!(a.read(c), &[0.5, 0.5, 0.0, 0.0]);
+        }
+        assert_eq!(analytic.independence_error(i, 3), Ok(Some(0.0)));
+        assert_eq!(analytic.independence_error(c, 3), Ok(Some(0.5)));
+        assert_eq!(analytic.independence_error(32, 3), Err(error::Error::NoSuchComputed { computed: 32, count: 8 }));
+        exact.run_generations(8).unwrap();
+        assert_eq!(exact.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn it_evolves_with_validated_normalization() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::<f64>::with_precision(samples, 2, 16).unwrap();
+        evolver.set_normalization(evolver::Normalization::Validate { tolerance: 1e-9 });
+        evolver.run_generations(8).unwrap();
+        assert!(evolver.worst_drift() <= 1e-9);
+    }
+
+    #[test]
+    fn it_reports_drift_past_the_tolerance() {
+        use operator::Operator::*;
+        use operator::Categorical;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        // Comparing two choices between sevenths rounds in `f32`, but not in `f64`.
+        let sevenths = Ambiguity(Categorical::new(&[1.0, 2.0, 4.0]).unwrap());
+        let seed = [Initial, Initial, Initial, sevenths, sevenths, Equality(3, 4)];
+        let validate = evolver::Normalization::Validate { tolerance: 1e-9 };
+        let mut evolver = evolver::Evolver::new(samples, 2, 16).unwrap();
+        evolver.set_normalization(validate);
+        let error = evolver.seed(&seed, false).unwrap_err();
+        assert!(matches!(error, error::Error::Drift { sample: 0, computed: 5, .. }), "{:?}", error);
+        assert_eq!(evolver.survivors(), 3);
+        assert_eq!(evolver.check_invariants(), Ok(()));
+        let mut precise = evolver::Evolver::<f64>::with_precision(samples, 2, 16).unwrap();
+        precise.set_normalization(validate);
+        assert!(precise.seed(&seed, false).is_ok());
+    }
+
+    #[test]
+    fn it_inserts_and_extracts_programs() {
+        use operator::Operator;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 16).unwrap();
+        let program = program::Program::new(vec![Operator::Initial, Operator::Initial, Operator::Initial,
+                                                 Operator::Not(1), Operator::Equality(2, 3), Operator::Ite(4, 3, 1)],
+                                            3).unwrap();
+        let root = evolver.insert(&program).unwrap();
+        assert_eq!(evolver.extract(root).unwrap(), program);
+        let mut full = evolver::Evolver::new(samples, 2, 4).unwrap();
+        assert_eq!(full.insert(&program), Err(error::Error::PopulationFull));
+    }
+
+    #[test]
+    fn it_rejects_misuse() {
+        use error::Error;
+        use operator::Operator::*;
+        let mut distributions = computed_distributions::ComputedDistributions::new(2, 2);
+        assert_eq!(Initial.run(1, &mut distributions), Err(Error::InitialOperator));
+        assert_eq!(Not(2).run(1, &mut distributions), Err(Error::OutOfBounds { computed: 2, count: 2 }));
+        assert_eq!(distributions.store(0, &[1.0]), Err(Error::DistributionLength { expected: 3, found: 1 }));
+        assert_eq!(distributions.store(2, &[1.0, 0.0, 0.0]), Err(Error::OutOfBounds { computed: 2, count: 2 }));
+        assert_eq!(And(0, 1).relocate(&[Some(0)]), Err(Error::MissingRelocation { computed: 1 }));
+        assert_eq!(program::Program::new(vec![], 0), Err(Error::EmptyProgram));
+        assert_eq!(program::Program::new(vec![Initial, Not(0), Initial], 1), Err(Error::MisplacedInitial { computed: 2 }));
+        assert_eq!(program::Program::new(vec![Initial, Not(1)], 1), Err(Error::ForwardReference { computed: 1, dependent: 1 }));
+        assert_eq!(evolver::Evolver::new(&[], 2, 16).err(), Some(Error::NoSamples));
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 16).unwrap();
+        let program = program::Program::new(vec![Initial, Not(0)], 1).unwrap();
+        assert_eq!(evolver.insert(&program), Err(Error::InputSize { expected: 3, found: 1 }));
+        assert_eq!(evolver.extract(3), Err(Error::NoSuchComputed { computed: 3, count: 3 }));
+        assert_eq!(evolver.is_protected(16), Err(Error::NoSuchComputed { computed: 16, count: 3 }));
+        assert_eq!(evolver.type_of(3), Err(Error::NoSuchComputed { computed: 3, count: 3 }));
+        assert_eq!(evolver.label(3), Err(Error::NoSuchComputed { computed: 3, count: 3 }));
+        assert_eq!(evolver.write_dot(&mut String::new(), None, Some(1)), Err(Error::NoSuchSample { sample: 1, sample_count: 1 }));
+        assert_eq!(evolver.extract(2).map(|program| program.root()), Ok(2));
+    }
+
+    #[test]
+    fn it_validates_samples() {
+        use error::Error;
+        let new = |samples: &[&[&[usize]]], population_size| evolver::Evolver::new(samples, 2, population_size).err();
+        assert_eq!(new(&[&[]], 16), Some(Error::NoSamples));
+        assert_eq!(new(&[&[&[]]], 16), Some(Error::EmptyState { sample: 0, state: 0 }));
+        assert_eq!(new(&[&[&[2, 0, 0]], &[&[2, 1, 1], &[2, 0]]], 16),
+                   Some(Error::StateWidth { sample: 1, state: 1, expected: 3, found: 2 }));
+        assert_eq!(new(&[&[&[2, 0, 0], &[2, 3, 1]]], 16), Some(Error::StateValue { sample: 0, state: 1, value: 3, max_value: 2 }));
+        assert_eq!(new(&[&[&[2, 0, 0]], &[&[2, 1, 1]]], 16), Some(Error::NoTransitions));
+        assert_eq!(new(&[&[&[2, 0, 0], &[2, 1, 1]]], 3), Some(Error::PopulationTooSmall { population_size: 3, input_size: 3 }));
+        assert!(new(&[&[&[2, 0, 0], &[2, 1, 1]]], 4).is_none());
+        let mut distributions = computed_distributions::ComputedDistributions::new(2, 2);
+        assert_eq!(distributions.set_values(0, &[0, 2]), Err(Error::ValueOutOfDomain { value: 2, size: 2 }));
+        assert_eq!(distributions.set_values(1, &[0, 1]), Err(Error::OutOfBounds { computed: 2, count: 2 }));
+    }
+
+    #[test]
+    fn it_keeps_protected_seeds() {
+        use operator::Operator::*;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 32).unwrap();
+        // Always false, so it would score badly.
+        let seed = [Initial, Initial, Initial, Not(0), And(0, 3), Increment(4)];
+        let root = evolver.seed(&seed, true).unwrap();
+        assert!(evolver.is_protected(root).unwrap());
+        assert!(!evolver.is_protected(0).unwrap());
+        evolver.run_generations(10).unwrap();
+        let program = program::Program::new(seed.to_vec(), 3).unwrap();
+        let kept = (0..evolver.survivors()).find(|&i| evolver.extract(i).unwrap() == program);
+        assert!(kept.is_some_and(|i| evolver.is_protected(i).unwrap()));
+    }
+
+    #[test]
+    fn it_evolves_with_every_credit_rule() {
+        use score::Credit;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        for &credit in &[Credit::Max, Credit::Sum, Credit::Decayed { factor: 0.5 }] {
+            let mut evolver = evolver::Evolver::new(samples, 2, 64).unwrap();
+            evolver.set_credit(credit);
+            evolver.run_generations(8).unwrap();
+            assert!(evolver.survivors() > 3);
+        }
+    }
+
+    #[test]
+    fn it_evolves_typed_programs() {
+        use operator::Operator::*;
+        use types::Type;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 64).unwrap();
+        assert_eq!(evolver.set_input_types(&[Type::Enum(0)]), Err(error::Error::InputSize { expected: 3, found: 1 }));
+        evolver.set_input_types(&[Type::Enum(0), Type::Bool, Type::Int]).unwrap();
+        assert_eq!(evolver.seed(&[Initial, Initial, Initial, And(2, 1)], false), Err(error::Error::IllTyped { computed: 3 }));
+        let root = evolver.seed(&[Initial, Initial, Initial, Not(1)], false).unwrap();
+        assert_eq!(evolver.type_of(root), Ok(Some(Type::Bool)));
+        evolver.run_generations(8).unwrap();
+        assert_eq!(evolver.check_invariants(), Ok(()));
+        assert!(evolver.survivors() > 3);
+    }
 
+    #[test]
+    fn it_evolves_from_a_grammar() {
+        use operator::Operator::*;
+        let grammar = grammar::Grammar::parse("winner ::= Ite(test, winner, winner) | input[0] | Value(0) | Value(2)
+test ::= Equality(counter, counter) | Not(player)
+counter ::= input[2] | Value(2)
+player ::= input[1]", 3).unwrap();
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 256).unwrap();
+        assert_eq!(evolver.set_start_symbol(0, "winner"), Err(error::Error::NoSuchSymbol { name: "winner".to_owned() }));
+        assert!(evolver.set_grammar(grammar::Grammar::parse("a ::= input", 2).unwrap()).is_err());
+        assert_eq!(evolver.set_grammar(grammar::Grammar::parse("a ::= Value(3)", 3).unwrap()),
+                   Err(error::Error::ValueOutOfDomain { value: 3, size: 3 }));
+        evolver.set_grammar(grammar).unwrap();
+        assert_eq!(evolver.set_start_symbol(3, "winner"), Err(error::Error::NoSuchOutput { output: 3, output_size: 3 }));
+        assert!(evolver.set_start_symbol(0, "loser").is_err());
+        evolver.set_start_symbol(0, "winner").unwrap();
+        evolver.run_generations(8).unwrap();
+        for i in 3..evolver.survivors() {
+            let program = evolver.extract(i).unwrap();
+            for operator in program.operators().iter() {
+                assert!(matches!(*operator, Initial | Value(_) | Ite(_, _, _) | Equality(_, _) | Not(_)));
+            }
+            if let Some(&Not(x)) = program.operators().last() {
+                assert_eq!(program.operators()[x], Initial);
+            }
+        }
+        // The winner is always predicted by an if-then-else chain of winners.
+        let (best, _) = evolver.best_computed(0).unwrap().unwrap();
+        assert_eq!(evolver.label(best), Ok(Some("winner")));
+        assert!(matches!(evolver.extract(best).unwrap().operators().last(), Some(&Ite(_, _, _))));
+    }
+
+    #[test]
+    fn it_restricts_outputs_to_their_inputs() {
+        use score::InputMask;
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 64).unwrap();
+        assert_eq!(evolver.set_input_mask(3, InputMask::new(3)),
+                   Err(error::Error::NoSuchOutput { output: 3, output_size: 3 }));
+        assert!(evolver.set_input_mask(0, InputMask::new(2)).is_err());
+        // Nothing has been generated yet, so no computed may be a program.
+        assert_eq!(evolver.best_computed(0), Ok(None));
+        assert_eq!(evolver.best_computed(3), Err(error::Error::NoSuchOutput { output: 3, output_size: 3 }));
+        assert_eq!(evolver.best_programs(), Err(error::Error::NoProgram { output: 0 }));
+        evolver.set_input_mask(1, InputMask::new(3).allow_only(&[1]).unwrap().require(&[1]).unwrap()).unwrap();
+        evolver.set_input_mask(2, InputMask::new(3).require(&[2]).unwrap()).unwrap();
+        evolver.run_generations(10).unwrap();
+        let programs = evolver.best_programs().unwrap();
+        let uses = |program: &program::Program| score::input_dependencies(program.operators(), 3)[program.root()].clone();
+        assert_eq!(uses(&programs[1]), vec![false, true, false]);
+        assert!(uses(&programs[2])[2]);
+    }
+
+    #[test]
+    fn it_removes_duplicates() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]],
+                                        &[&[2, 0, 0], &[2, 1, 2], &[1, 0, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 64).unwrap();
+        let mut removed = 0;
+        for _ in 0..8 {
+            evolver.run_generations(1).unwrap();
+            removed += evolver.duplicates_removed();
+            let dists = evolver.distributions();
+            for i in 0..evolver.survivors() {
+                for j in 0..i {
+                    assert!(dists.iter().any(|d| d.read(i) != d.read(j)), "{} duplicates {}", i, j);
+                }
+            }
+        }
+        assert!(removed > 0);
+    }
+
+    #[test]
+    fn it_writes_populations_as_dot() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 16).unwrap();
+        evolver.run_generations(2).unwrap();
+        let mut dot = String::new();
+        evolver.write_dot(&mut dot, None, Some(0)).unwrap();
+        let nodes = dot.lines().filter(|line| line.contains("[label") && !line.contains("->")).count();
+        assert_eq!(nodes, evolver.survivors());
+        let mut program_dot = String::new();
+        let last = evolver.survivors() - 1;
+        evolver.write_dot(&mut program_dot, Some(last), None).unwrap();
+        assert!(program_dot.contains(&format!("n{} [label=\"{}: ", last, last)));
+    }
+
+    #[test]
+    fn it_writes_best_programs() {
+        let samples: &[&[&[usize]]] = &[&[&[2, 0, 0], &[2, 1, 1], &[2, 0, 2], &[0, 1, 2]]];
+        let mut evolver = evolver::Evolver::new(samples, 2, 16).unwrap();
+        evolver.run_generations(2).unwrap();
+        let mut out = Vec::new();
+        evolver.write_best(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.matches("best program").count(), 3);
+        assert_eq!(text.matches("predicted").count(), 9);
+    }
 
     #[test]
     fn it_evolves_122() {
@@ -88,6 +475,6 @@ mod tests {
                    &[1, 0, 2]]],
                2,
                16,
-               128);
+               128).unwrap();
     }
 }
@@ -0,0 +1,298 @@
+//! Runs evolution and works with learned programs from the command line.
+//!
+//! Trace files hold one state per line, as whitespace separated values, with blank lines between
+//! traces. Program files start with `inputs N` and `max_value N` lines, followed by one infix
+//! program per output. Config files hold `key = value` lines. In every file, lines starting with
+//! `#` are ignored.
+extern crate rvlvr;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use rvlvr::evaluate::{evaluate_programs};
+use rvlvr::evolver::{EvaluationMode, Evolver};
+use rvlvr::format::{Style};
+use rvlvr::parse::{parse_program};
+use rvlvr::program::{Program};
+use rvlvr::simplify::{simplify};
+
+const USAGE: &str = "usage:
+    rvlvr evolve <traces> <config> <output>
+    rvlvr eval <programs> <traces>
+    rvlvr simplify <programs>
+    rvlvr print <programs> [functional|infix|sexp]";
+
+enum Error {
+    /// The command line was invalid. Exits with status 2.
+    Usage(String),
+    /// Reading, parsing or writing a file failed. Exits with status 1.
+    Failed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usage(ref message) => write!(f, "{}\n{}", message, USAGE),
+            Error::Failed(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(error) = run(&args) {
+        eprintln!("rvlvr: {}", error);
+        process::exit(match error {
+            Error::Usage(_) => 2,
+            Error::Failed(_) => 1,
+        });
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+    match args.as_slice() {
+        ["evolve", traces, config, output] => evolve(traces, config, output),
+        ["eval", programs, traces] => eval(programs, traces),
+        ["simplify", programs] => {
+            let (programs, input_size, max_value) = read_programs(programs)?;
+            let simplified: Vec<Program> = programs.iter().map(|p| simplify(p, max_value + 1)).collect();
+            print!("{}", format_programs(&simplified, input_size, max_value, Style::Infix));
+            Ok(())
+        },
+        ["print", programs] => print(programs, "infix"),
+        ["print", programs, style] => print(programs, style),
+        [] => Err(Error::Usage("missing command".to_owned())),
+        _ => Err(Error::Usage(format!("invalid command `{}`", args.join(" ")))),
+    }
+}
+
+fn evolve(traces: &str, config: &str, output: &str) -> Result<(), Error> {
+    let traces = read_traces(traces)?;
+    let config = Config::parse(&read_file(config)?)?;
+    let samples: Vec<Vec<&[usize]>> = traces.iter().map(|t| t.iter().map(|s| s.as_slice()).collect()).collect();
+    let samples: Vec<&[&[usize]]> = samples.iter().map(|s| s.as_slice()).collect();
+    let max_value = config.max_value.unwrap_or_else(|| max_trace_value(&traces));
+    let mut evolver = Evolver::new(&samples, max_value, config.population_size).map_err(failed)?;
+    evolver.set_evaluation_mode(config.evaluation_mode);
+    evolver.set_deduplication(config.deduplicate);
+    for generation in 0..config.generations {
+        evolver.run_generations(1).map_err(failed)?;
+        let scores: Vec<String> = (0..evolver.output_size())
+            .map(|output| Ok(evolver.best_computed(output)?
+                .map_or("-".to_owned(), |(_, score)| format!("{:.3}", score))))
+            .collect::<rvlvr::error::Result<_>>().map_err(failed)?;
+        eprintln!("generation {}: {} survivors, {} duplicates removed, best scores [{}]",
+                  generation, evolver.survivors(), evolver.duplicates_removed(), scores.join(", "));
+    }
+    // The best programs come with the distributions of their random choices fitted.
+    let programs = evolver.best_programs().map_err(failed)?;
+    let text = format_programs(&programs, traces[0][0].len(), max_value, Style::Infix);
+    fs::write(output, text).map_err(|e| Error::Failed(format!("cannot write {}: {}", output, e)))
+}
+
+fn eval(programs: &str, traces: &str) -> Result<(), Error> {
+    let (programs, input_size, max_value) = read_programs(programs)?;
+    let traces = read_traces(traces)?;
+    if traces[0][0].len() != input_size {
+        return Err(Error::Failed(format!("programs have {} inputs, but traces have {} values", input_size,
+                                         traces[0][0].len())));
+    }
+    let samples: Vec<Vec<&[usize]>> = traces.iter().map(|t| t.iter().map(|s| s.as_slice()).collect()).collect();
+    let samples: Vec<&[&[usize]]> = samples.iter().map(|s| s.as_slice()).collect();
+    for (output, evaluation) in evaluate_programs(&programs, &samples, max_value).map_err(failed)?.iter().enumerate() {
+        println!("output {}: score {}, portion correct {}, log mse {}, complexity {}", output, evaluation.score,
+                 evaluation.portion_correct, evaluation.log_mse, evaluation.complexity);
+        for row in evaluation.confusion.iter() {
+            let counts: Vec<String> = row.iter().map(|c| c.to_string()).collect();
+            println!("    {}", counts.join(" "));
+        }
+    }
+    Ok(())
+}
+
+fn print(programs: &str, style: &str) -> Result<(), Error> {
+    let style = match style {
+        "functional" => Style::Functional,
+        "infix" => Style::Infix,
+        "sexp" => Style::SExpression,
+        _ => return Err(Error::Usage(format!("unknown style `{}`", style))),
+    };
+    let (programs, _, _) = read_programs(programs)?;
+    for program in programs.iter() {
+        println!("{}", program.display(style));
+    }
+    io::stdout().flush().map_err(|e| Error::Failed(e.to_string()))
+}
+
+fn failed(error: rvlvr::error::Error) -> Error {
+    Error::Failed(error.to_string())
+}
+
+fn read_file(path: &str) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|e| Error::Failed(format!("cannot read {}: {}", path, e)))
+}
+
+/// Lines which aren't comments, with their line numbers.
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines().enumerate().map(|(n, line)| (n + 1, line.trim())).filter(|&(_, line)| !line.starts_with('#'))
+}
+
+fn read_traces(path: &str) -> Result<Vec<Vec<Vec<usize>>>, Error> {
+    parse_traces(&read_file(path)?).map_err(|e| Error::Failed(format!("{}: {}", path, e)))
+}
+
+fn parse_traces(text: &str) -> Result<Vec<Vec<Vec<usize>>>, String> {
+    let mut traces = vec![Vec::new()];
+    for (n, line) in lines(text) {
+        if line.is_empty() {
+            if !traces[traces.len() - 1].is_empty() {
+                traces.push(Vec::new());
+            }
+            continue;
+        }
+        let state = line.split_whitespace()
+            .map(|v| v.parse().map_err(|_| format!("line {}: invalid value `{}`", n, v)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        traces.last_mut().expect("there is always a trace").push(state);
+    }
+    traces.retain(|t| !t.is_empty());
+    let width = traces.first().map(|t| t[0].len()).ok_or_else(|| "no traces".to_owned())?;
+    if traces.iter().any(|t| t.len() < 2) {
+        return Err("every trace needs at least two states".to_owned());
+    }
+    if traces.iter().any(|t| t.iter().any(|s| s.len() != width)) {
+        return Err(format!("every state needs {} values", width));
+    }
+    Ok(traces)
+}
+
+fn max_trace_value(traces: &[Vec<Vec<usize>>]) -> usize {
+    traces.iter().flat_map(|t| t.iter()).flat_map(|s| s.iter()).cloned().max().unwrap_or(0)
+}
+
+fn read_programs(path: &str) -> Result<(Vec<Program>, usize, usize), Error> {
+    parse_programs(&read_file(path)?).map_err(|e| Error::Failed(format!("{}: {}", path, e)))
+}
+
+/// Parses a program file, returning the programs, the number of inputs and the maximum value.
+fn parse_programs(text: &str) -> Result<(Vec<Program>, usize, usize), String> {
+    let mut input_size = None;
+    let mut max_value = None;
+    let mut programs = Vec::new();
+    for (n, line) in lines(text).filter(|&(_, line)| !line.is_empty()) {
+        let mut words = line.split_whitespace();
+        let header = match words.next() {
+            Some("inputs") => &mut input_size,
+            Some("max_value") => &mut max_value,
+            _ => {
+                let input_size = input_size.ok_or_else(|| format!("line {}: expected `inputs` first", n))?;
+                let program = parse_program(line, Style::Infix, input_size).map_err(|e| format!("line {}, {}", n, e))?;
+                programs.push(program);
+                continue;
+            },
+        };
+        *header = Some(parse_number(words.next(), n)?);
+    }
+    match (input_size, max_value) {
+        (Some(input_size), Some(max_value)) => Ok((programs, input_size, max_value)),
+        _ => Err("expected `inputs` and `max_value` lines".to_owned()),
+    }
+}
+
+fn format_programs(programs: &[Program], input_size: usize, max_value: usize, style: Style) -> String {
+    let mut text = format!("inputs {}\nmax_value {}\n", input_size, max_value);
+    for program in programs.iter() {
+        text += &format!("{}\n", program.display(style));
+    }
+    text
+}
+
+fn parse_number(word: Option<&str>, line: usize) -> Result<usize, String> {
+    word.and_then(|w| w.parse().ok()).ok_or_else(|| format!("line {}: expected a number", line))
+}
+
+struct Config {
+    max_value: Option<usize>,
+    population_size: usize,
+    generations: usize,
+    evaluation_mode: EvaluationMode,
+    deduplicate: bool,
+}
+
+impl Config {
+    /// Parses `key = value` lines. `evaluation` is `analytic`, `sampling` (using `traces`) or
+    /// `exact` (using `max_random`).
+    fn parse(text: &str) -> Result<Config, Error> {
+        let mut config = Config {
+            max_value: None,
+            population_size: 128,
+            generations: 100,
+            evaluation_mode: EvaluationMode::Analytic,
+            deduplicate: true,
+        };
+        let mut evaluation = "analytic".to_owned();
+        let mut traces = 64;
+        let mut max_random = 8;
+        for (n, line) in lines(text).filter(|&(_, line)| !line.is_empty()) {
+            let invalid = || Error::Failed(format!("config line {}: invalid setting `{}`", n, line));
+            let mut parts = line.splitn(2, '=').map(|part| part.trim());
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(invalid()),
+            };
+            let number = || value.parse::<usize>().map_err(|_| invalid());
+            match key {
+                "max_value" => config.max_value = Some(number()?),
+                "population_size" => config.population_size = number()?,
+                "generations" => config.generations = number()?,
+                "traces" => traces = number()?,
+                "max_random" => max_random = number()?,
+                "evaluation" => evaluation = value.to_owned(),
+                "deduplicate" => config.deduplicate = value.parse().map_err(|_| invalid())?,
+                _ => return Err(Error::Failed(format!("config line {}: unknown setting `{}`", n, key))),
+            }
+        }
+        config.evaluation_mode = match evaluation.as_ref() {
+            "analytic" => EvaluationMode::Analytic,
+            "sampling" => EvaluationMode::Sampling { traces },
+            "exact" => EvaluationMode::Exact { max_random },
+            _ => return Err(Error::Failed(format!("unknown evaluation `{}`", evaluation))),
+        };
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_traces() {
+        let traces = parse_traces("# 122\n2 0 0\n2 1 1\n\n\n2 0 0\n2 1 2\n").unwrap();
+        assert_eq!(traces, vec![vec![vec![2, 0, 0], vec![2, 1, 1]], vec![vec![2, 0, 0], vec![2, 1, 2]]]);
+        assert_eq!(parse_traces("2 0\n2 x\n"), Err("line 2: invalid value `x`".to_owned()));
+        assert!(parse_traces("2 0\n2\n").is_err());
+        assert!(parse_traces("2 0\n").is_err());
+    }
+
+    #[test]
+    fn it_round_trips_program_files() {
+        let text = "inputs 3\nmax_value 2\nif input[2] == 2 { input[1] } else { input[0] }\n!input[1]\n";
+        let (programs, input_size, max_value) = parse_programs(text).unwrap();
+        assert_eq!((programs.len(), input_size, max_value), (2, 3, 2));
+        assert_eq!(format_programs(&programs, input_size, max_value, Style::Infix), text);
+        assert_eq!(parse_programs("inputs 1\nmax_value 1\ninput[1]"),
+                   Err("line 3, column 7: input 1 does not exist".to_owned()));
+    }
+
+    #[test]
+    fn it_parses_configs() {
+        let config = Config::parse("generations = 3\nevaluation = exact\nmax_random = 4\n").ok().unwrap();
+        assert_eq!(config.generations, 3);
+        assert_eq!(config.evaluation_mode, EvaluationMode::Exact { max_random: 4 });
+        assert!(Config::parse("colour = blue").is_err());
+    }
+}
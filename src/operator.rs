@@ -1,19 +1,111 @@
+use std::fmt;
 use rand;
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::error::{Error, Result};
+use super::format::{Formatted, Style};
 
 /// An operator in a generated program.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Operator {
     Initial,
     Value(usize),
     Equality(usize, usize),
     Increment(usize),
-    Ambiguity(usize),
+    /// A random choice of value, independent of every other.
+    Ambiguity(Categorical),
     And(usize, usize),
     Or(usize, usize),
     Not(usize),
     Ite(usize, usize, usize),
 }
 
+/// The number of values a `Categorical` can choose between. Larger values of the domain are never
+/// chosen.
+pub const CATEGORICAL_VALUES: usize = 12;
+
+/// The total weight of a `Categorical`, so probabilities are multiples of 0.0001.
+const CATEGORICAL_TOTAL: u32 = 10_000;
+
+/// A distribution over the values `0..CATEGORICAL_VALUES`, stored as integer weights so that
+/// operators stay `Copy` and can be hashed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Categorical {
+    weights: [u16; CATEGORICAL_VALUES],
+}
+
+impl Categorical {
+    /// A fair choice between 0 and 1.
+    pub const COIN: Categorical = Categorical { weights: [5_000, 5_000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] };
+
+    /// Normalizes the probabilities of values `0..probabilities.len()`, rounding them to
+    /// multiples of 0.0001. Returns `None` if there are too many values, any probability is
+    /// negative, or none is positive.
+    pub fn new(probabilities: &[f64]) -> Option<Categorical> {
+        let total: f64 = probabilities.iter().sum();
+        let valid = probabilities.iter().all(|&p| p >= 0.0) && total > 0.0 && total.is_finite();
+        if probabilities.len() > CATEGORICAL_VALUES || !valid {
+            return None;
+        }
+        // Round down, then give the remaining weight to the largest remainders, so the weights
+        // always sum to the total.
+        let scaled: Vec<f64> = probabilities.iter().map(|p| p / total * CATEGORICAL_TOTAL as f64).collect();
+        let mut weights = [0; CATEGORICAL_VALUES];
+        for (weight, &x) in weights.iter_mut().zip(scaled.iter()) {
+            *weight = x.floor() as u16;
+        }
+        let assigned: u32 = weights.iter().map(|&w| w as u32).sum();
+        let mut by_remainder: Vec<usize> = (0..scaled.len()).collect();
+        by_remainder.sort_by(|&a, &b| (scaled[b] - scaled[b].floor()).total_cmp(&(scaled[a] - scaled[a].floor())));
+        for &v in by_remainder.iter().take((CATEGORICAL_TOTAL - assigned) as usize) {
+            weights[v] += 1;
+        }
+        Some(Categorical { weights })
+    }
+
+    pub fn probability(&self, value: usize) -> f64 {
+        self.weights.get(value).map_or(0.0, |&w| w as f64 / CATEGORICAL_TOTAL as f64)
+    }
+
+    /// The number of values up to and including the largest with a non-zero probability.
+    pub fn support(&self) -> usize {
+        self.weights.iter().rposition(|&w| w > 0).map_or(0, |v| v + 1)
+    }
+
+    /// The probabilities of the values in the support.
+    pub fn probabilities(&self) -> Vec<f64> {
+        (0..self.support()).map(|v| self.probability(v)).collect()
+    }
+
+    /// The only value with a non-zero probability, if there is one.
+    pub fn constant(&self) -> Option<usize> {
+        self.weights.iter().position(|&w| w as u32 == CATEGORICAL_TOTAL)
+    }
+
+    pub fn sample<R>(&self, rand_gen: &mut R) -> usize where R: rand::Rng {
+        let mut remaining = rand_gen.next_u32() % CATEGORICAL_TOTAL;
+        for (v, &w) in self.weights.iter().enumerate() {
+            if remaining < w as u32 {
+                return v;
+            }
+            remaining -= w as u32;
+        }
+        panic!("categorical weights must sum to the total")
+    }
+}
+
+/// Writes the probabilities separated by `, `, always with a decimal point.
+impl fmt::Display for Categorical {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (v, p) in self.probabilities().iter().enumerate() {
+            if v > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:?}", p)?;
+        }
+        Ok(())
+    }
+}
+
 impl Operator {
     pub fn dependents(&self) -> [Option<usize>; 3] {
         match *self {
@@ -24,13 +116,28 @@ impl Operator {
         }
     }
 
+    /// The name of the operator's variant.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Operator::Initial => "Initial",
+            Operator::Value(_) => "Value",
+            Operator::Equality(_, _) => "Equality",
+            Operator::Increment(_) => "Increment",
+            Operator::Ambiguity(_) => "Ambiguity",
+            Operator::And(_, _) => "And",
+            Operator::Or(_, _) => "Or",
+            Operator::Not(_) => "Not",
+            Operator::Ite(_, _, _) => "Ite",
+        }
+    }
+
     pub fn new_rand<R>(rand_gen: &mut R, output_idx: usize) -> Operator where R: rand::Rng {
         let total = 64;
-        let mut op_idx = rand_gen.next_u32() % total;
+        let op_idx = rand_gen.next_u32() % total;
         if false {
             panic!("no possible");
         } else if op_idx < 1 {
-            Operator::Ambiguity(0)
+            Operator::Ambiguity(Categorical::COIN)
         } else if op_idx < 2 {
             Operator::Value(0)
         } else if op_idx < 3 {
@@ -61,7 +168,7 @@ impl Operator {
             1 => Operator::Value(1),
             2 => Operator::Equality(rand_idx(rand_gen, output_idx), rand_idx(rand_gen, output_idx)),
             3 => Operator::Increment(rand_idx(rand_gen, output_idx)),
-            4 => Operator::Ambiguity(0),
+            4 => Operator::Ambiguity(Categorical::COIN),
             5 => Operator::And(rand_idx(rand_gen, output_idx), rand_idx(rand_gen, output_idx)),
             6 => Operator::Or(rand_idx(rand_gen, output_idx), rand_idx(rand_gen, output_idx)),
             7 => Operator::Not(rand_idx(rand_gen, output_idx)),
@@ -71,22 +178,50 @@ impl Operator {
         }
     }
 
+    /// Computes the value of this operator given the concrete values of its dependents, in the
+    /// same order as `dependents()`.
     fn exec<R>(&self, args: [Option<usize>; 3], rand_gen: &mut R) -> usize where R: rand::Rng {
-        0
-        //Operator::Initial => panic!("cannot perform Initial operator"),
-        //Operator::Value(i) => i,
-        //Operator::Equality(x, y) => if trace[x] == trace[y] { 1 } else { 0 },
-        //Operator::Increment(x) => trace[x] + 1,
-        //Operator::Ambiguity(_) => (rand_gen.next_u32() & 0x1) as i32,
-        //Operator::And(x, y) => if trace[x] != 0 && trace[y] != 0 { 1 } else { 0 },
-        //Operator::Or(x, y) => if trace[x] != 0 || trace[y] != 0 { 1 } else { 0 },
-        //Operator::Not(x) => if trace[x] == 0 { 1 } else { 0 },
-        //Operator::Ite(x, y, z) => if trace[x] == 0 { trace[y] } else { trace[z] },
+        let arg = |n: usize| args[n].expect("missing argument value");
+        match *self {
+            Operator::Initial => panic!("cannot perform Initial operator"),
+            Operator::Value(i) => i,
+            Operator::Equality(_, _) => if arg(0) == arg(1) { 1 } else { 0 },
+            Operator::Increment(_) => arg(0) + 1,
+            Operator::Ambiguity(c) => c.sample(rand_gen),
+            Operator::And(_, _) => if arg(0) != 0 && arg(1) != 0 { 1 } else { 0 },
+            Operator::Or(_, _) => if arg(0) != 0 || arg(1) != 0 { 1 } else { 0 },
+            Operator::Not(_) => if arg(0) != 0 { 0 } else { 1 },
+            Operator::Ite(_, _, _) => if arg(0) != 0 { arg(1) } else { arg(2) },
+        }
     }
 
-    pub fn run(&self, target: usize, dists: &mut super::computed_distributions::ComputedDistributions) {
+    /// Like `exec`, but follows the rules for undefined values used by `run`, for a domain of
+    /// `size` values. Arguments equal to `size` are undefined, as is the result if it is `size`.
+    pub(crate) fn exec_in_domain<R>(&self, args: [Option<usize>; 3], size: usize, rand_gen: &mut R) -> usize
+        where R: rand::Rng {
+        let undefined = |n: usize| args[n].is_some_and(|x| x >= size);
+        let out = match *self {
+            // Only the taken branch needs to be defined.
+            Operator::Ite(_, _, _) if !undefined(0) => self.exec(args, rand_gen),
+            // If any input in undefined, the output is undefined.
+            _ if undefined(0) || undefined(1) || undefined(2) => size,
+            _ => self.exec(args, rand_gen),
+        };
+        // If out is too large, then it's not defined.
+        usize::min(out, size)
+    }
+
+    /// Computes the distribution of `target` from the distributions of this operator's
+    /// dependents.
+    pub fn run<P>(&self, target: usize, dists: &mut ComputedDistributions<P>) -> Result<()> where P: Probability {
+        dists.check_bounds(target)?;
+        for dep in self.dependents().iter() {
+            if let Some(x) = *dep {
+                dists.check_bounds(x)?;
+            }
+        }
         match *self {
-            Operator::Initial => panic!("cannot run Initial operator"),
+            Operator::Initial => return Err(Error::InitialOperator),
             Operator::Value(i) => dists.compute_at_0(target, || i),
             Operator::Equality(x, y) => dists.compute_at_2(target, (x, y),
                 |x, y| {
@@ -102,41 +237,47 @@ impl Operator {
                 }),
             Operator::Increment(x) => dists.compute_at_1(target, x, |x| x + 1),
             Operator::Not(x) => dists.compute_at_1(target, x, |x| if x != 0 { 0 } else { 1 }),
-            Operator::Ite(x, y, z) => dists.compute_at_3(target, (x, y, z),
-                |x, y, z| {
-                    if x != 0 {
-                        y
-                    } else {
-                        z
-                    }
-                }),
-            Operator::Ambiguity(_) => dists.compute_at_0_prob(target,
+            Operator::Ite(x, y, z) => {
+                // Only the taken branch needs to be defined.
+                let undefined = dists.size();
+                dists.compute_at_lazy(target, &[x, y, z],
+                    |args| {
+                        if args[0] == undefined {
+                            undefined
+                        } else if args[0] != 0 {
+                            args[1]
+                        } else {
+                            args[2]
+                        }
+                    })
+            },
+            Operator::Ambiguity(c) => dists.compute_at_0_prob(target,
                 |out| {
-                    out[0] = 0.5;
-                    out[1] = 0.5;
+                    // Values outside of the domain are undefined.
+                    let undefined = out.len() - 1;
+                    for v in 0..c.support() {
+                        out[usize::min(v, undefined)] += P::from_f64(c.probability(v));
+                    }
                 }),
         }
+        Ok(())
     }
 
-    pub fn relocate(&self, relocations: &[Option<usize>]) -> Self {
-        let reason = "All dependent operators should have been relocated.";
-        match *self {
-            Operator::Initial => panic!("cannot relocate Initial operator"),
+    /// Renumbers the dependents of this operator. Every dependent must have a relocation.
+    pub fn relocate(&self, relocations: &[Option<usize>]) -> Result<Self> {
+        let relocate = |x: usize| relocations.get(x).cloned().and_then(|r| r)
+            .ok_or(Error::MissingRelocation { computed: x });
+        Ok(match *self {
+            Operator::Initial => return Err(Error::InitialOperator),
             Operator::Value(i) => Operator::Value(i),
-            Operator::Ambiguity(i) => Operator::Ambiguity(i),
-            Operator::Increment(x) => Operator::Increment(relocations[x].expect(reason)),
-            Operator::Not(x) => Operator::Not(relocations[x].expect(reason)),
-            Operator::Equality(x, y) =>
-                Operator::Equality(relocations[x].expect(reason), relocations[y].expect(reason)),
-            Operator::And(x, y) =>
-                Operator::And(relocations[x].expect(reason), relocations[y].expect(reason)),
-            Operator::Or(x, y) =>
-                Operator::Or(relocations[x].expect(reason), relocations[y].expect(reason)),
-            Operator::Ite(x, y, z) =>
-                Operator::Ite(relocations[x].expect(reason),
-                              relocations[y].expect(reason),
-                              relocations[z].expect(reason)),
-        }
+            Operator::Ambiguity(c) => Operator::Ambiguity(c),
+            Operator::Increment(x) => Operator::Increment(relocate(x)?),
+            Operator::Not(x) => Operator::Not(relocate(x)?),
+            Operator::Equality(x, y) => Operator::Equality(relocate(x)?, relocate(y)?),
+            Operator::And(x, y) => Operator::And(relocate(x)?, relocate(y)?),
+            Operator::Or(x, y) => Operator::Or(relocate(x)?, relocate(y)?),
+            Operator::Ite(x, y, z) => Operator::Ite(relocate(x)?, relocate(y)?, relocate(z)?),
+        })
     }
 }
 
@@ -145,109 +286,9 @@ fn rand_idx<R>(rand_gen: &mut R, past_end: usize) -> usize where R: rand::Rng {
 }
 
 pub fn print_program(operators: &[Operator], i: usize) {
-    match operators[i] {
-        Operator::Initial => {
-            print!("Initial({})", i);
-        },
-        Operator::Value(i) => {
-            print!("Value({})", i);
-        },
-        Operator::Ambiguity(i) => {
-            print!("Ambiguity({})", i);
-        },
-        Operator::Increment(x) => {
-            print!("Increment(");
-            print_program(operators, x);
-            print!(")");
-        },
-        Operator::Not(x) => {
-            print!("Not(");
-            print_program(operators, x);
-            print!(")");
-        },
-        Operator::Equality(x, y) => {
-            print!("Equality(");
-            print_program(operators, x);
-            print!(", ");
-            print_program(operators, y);
-            print!(")");
-        },
-        Operator::And(x, y) => {
-            print!("And(");
-            print_program(operators, x);
-            print!(", ");
-            print_program(operators, y);
-            print!(")");
-        },
-        Operator::Or(x, y) => {
-            print!("Or(");
-            print_program(operators, x);
-            print!(", ");
-            print_program(operators, y);
-            print!(")");
-        },
-        Operator::Ite(x, y, z) => {
-            print!("Ite(");
-            print_program(operators, x);
-            print!(", ");
-            print_program(operators, y);
-            print!(", ");
-            print_program(operators, z);
-            print!(")");
-        },
-    }
+    print!("{}", Formatted::new(operators, i, Style::Functional));
 }
 
 pub fn pretty_print_program(operators: &[Operator], i: usize) {
-    match operators[i] {
-        Operator::Initial => {
-            print!("input[{}]", i);
-        },
-        Operator::Value(i) => {
-            print!("{}", i);
-        },
-        Operator::Ambiguity(i) => {
-            print!("ambiguous({})", i);
-        },
-        Operator::Increment(x) => {
-            print!("1 + (");
-            pretty_print_program(operators, x);
-            print!(")");
-        },
-        Operator::Not(x) => {
-            print!("!(");
-            pretty_print_program(operators, x);
-            print!(")");
-        },
-        Operator::Equality(x, y) => {
-            print!("(");
-            pretty_print_program(operators, x);
-            print!(") == (");
-            pretty_print_program(operators, y);
-            print!(")");
-        },
-        Operator::And(x, y) => {
-            print!("(");
-            pretty_print_program(operators, x);
-            print!(") && (");
-            pretty_print_program(operators, y);
-            print!(")");
-        },
-        Operator::Or(x, y) => {
-            print!("(");
-            pretty_print_program(operators, x);
-            print!(") || (");
-            pretty_print_program(operators, y);
-            print!(")");
-        },
-        Operator::Ite(x, y, z) => {
-            print!("if (");
-            pretty_print_program(operators, x);
-            print!(") {{ ");
-            pretty_print_program(operators, y);
-            print!(" }} else {{ ");
-            pretty_print_program(operators, z);
-            print!(" }}");
-        },
-    }
+    print!("{}", Formatted::new(operators, i, Style::Infix));
 }
@@ -0,0 +1,507 @@
+//! Parses programs written in any of the styles produced by `format`.
+//!
+//! Identical subexpressions are only stored once in the parsed program, except for `Ambiguity`,
+//! since each occurrence is a separate random choice.
+use std::collections::{HashMap};
+use std::error;
+use std::fmt;
+use super::format::{Style};
+use super::operator::{Categorical, Operator, CATEGORICAL_VALUES};
+use super::program::{Program};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The column, starting from 1, where the error was found.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a program with `input_size` inputs, written in the given style.
+pub fn parse_program(source: &str, style: Style, input_size: usize) -> Result<Program, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        operators: vec![Operator::Initial; input_size],
+        interned: HashMap::new(),
+    };
+    let root = match style {
+        Style::Functional => parser.functional()?,
+        Style::Infix => parser.infix()?,
+        Style::SExpression => parser.s_expression()?,
+    };
+    if parser.peek() != &Token::End {
+        return Err(parser.error("expected the end of the program"));
+    }
+    Ok(Program::extract(&parser.operators, input_size, root).expect("parsed operators are well formed"))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Number(usize),
+    /// A number with a decimal point, only used for probabilities.
+    Decimal(String),
+    Symbol(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Name(ref name) => write!(f, "`{}`", name),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Decimal(ref d) => write!(f, "`{}`", d),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+            Token::End => write!(f, "the end of the program"),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &["==", "&&", "||", "(", ")", "[", "]", "{", "}", ",", "!", "+"];
+
+/// Splits the source into tokens, each with the column it starts at.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                tokens.push((Token::Decimal(chars[start..i].iter().collect()), start + 1));
+                continue;
+            }
+            let digits: String = chars[start..i].iter().collect();
+            let number = digits.parse().map_err(|_| ParseError {
+                column: start + 1,
+                message: format!("`{}` is too large", digits),
+            })?;
+            tokens.push((Token::Number(number), start + 1));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Name(chars[start..i].iter().collect()), start + 1));
+        } else {
+            let rest: String = chars[i..usize::min(i + 2, chars.len())].iter().collect();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                Some(symbol) => {
+                    i += symbol.len();
+                    tokens.push((Token::Symbol(symbol), start + 1));
+                },
+                None => {
+                    return Err(ParseError {
+                        column: start + 1,
+                        message: format!("unexpected character `{}`", c),
+                    });
+                },
+            }
+        }
+    }
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    operators: Vec<Operator>,
+    interned: HashMap<Operator, usize>,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].0.clone();
+        if token != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            column: self.tokens[self.position].1,
+            message: format!("{}, found {}", message, self.peek()),
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ParseError> {
+        if self.peek() == &Token::Symbol(symbol) {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", symbol)))
+        }
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == &Token::Symbol(symbol) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        match *self.peek() {
+            Token::Number(n) => {
+                self.next();
+                Ok(n)
+            },
+            _ => Err(self.error("expected a number")),
+        }
+    }
+
+    /// Parses the probabilities of an `Ambiguity`, separated by commas if `separated` is set.
+    fn categorical(&mut self, separated: bool) -> Result<Categorical, ParseError> {
+        let start = self.position;
+        let mut probabilities = vec![self.probability()?];
+        while if separated { self.eat(",") } else { matches!(*self.peek(), Token::Number(_) | Token::Decimal(_)) } {
+            probabilities.push(self.probability()?);
+        }
+        Categorical::new(&probabilities).ok_or_else(|| {
+            self.position = start;
+            self.error(&format!("expected the probabilities of at most {} values, not all zero", CATEGORICAL_VALUES))
+        })
+    }
+
+    fn probability(&mut self) -> Result<f64, ParseError> {
+        let probability = match *self.peek() {
+            Token::Number(n) => n as f64,
+            Token::Decimal(ref d) => d.parse().expect("decimals are tokenized as digits around a point"),
+            _ => return Err(self.error("expected a probability")),
+        };
+        self.next();
+        Ok(probability)
+    }
+
+    fn input(&mut self) -> Result<usize, ParseError> {
+        let column = self.tokens[self.position].1;
+        let i = self.number()?;
+        if i < self.operators.iter().take_while(|&&op| op == Operator::Initial).count() {
+            Ok(i)
+        } else {
+            Err(ParseError {
+                column,
+                message: format!("input {} does not exist", i),
+            })
+        }
+    }
+
+    /// Adds an operator, reusing an identical existing one if possible.
+    fn add(&mut self, operator: Operator) -> usize {
+        if let Operator::Ambiguity(_) = operator {
+            // Each Ambiguity is a separate random choice, so they can't be shared.
+        } else if let Some(&existing) = self.interned.get(&operator) {
+            return existing;
+        }
+        self.operators.push(operator);
+        self.interned.insert(operator, self.operators.len() - 1);
+        self.operators.len() - 1
+    }
+
+    fn infix(&mut self) -> Result<usize, ParseError> {
+        let mut x = self.and()?;
+        while self.eat("||") {
+            let y = self.and()?;
+            x = self.add(Operator::Or(x, y));
+        }
+        Ok(x)
+    }
+
+    fn and(&mut self) -> Result<usize, ParseError> {
+        let mut x = self.equality()?;
+        while self.eat("&&") {
+            let y = self.equality()?;
+            x = self.add(Operator::And(x, y));
+        }
+        Ok(x)
+    }
+
+    fn equality(&mut self) -> Result<usize, ParseError> {
+        let x = self.increment()?;
+        if self.eat("==") {
+            let y = self.increment()?;
+            if self.peek() == &Token::Symbol("==") {
+                return Err(self.error("`==` is not associative, so expected parentheses"));
+            }
+            Ok(self.add(Operator::Equality(x, y)))
+        } else {
+            Ok(x)
+        }
+    }
+
+    fn increment(&mut self) -> Result<usize, ParseError> {
+        let adds_one = self.peek() == &Token::Number(1) && self.tokens[self.position + 1].0 == Token::Symbol("+");
+        if adds_one {
+            self.next();
+            self.next();
+            let x = self.increment()?;
+            Ok(self.add(Operator::Increment(x)))
+        } else {
+            let column = self.tokens[self.position].1;
+            let x = self.unary()?;
+            if self.peek() == &Token::Symbol("+") {
+                return Err(ParseError {
+                    column,
+                    message: "only `1 + x` is supported".to_owned(),
+                });
+            }
+            Ok(x)
+        }
+    }
+
+    fn unary(&mut self) -> Result<usize, ParseError> {
+        if self.eat("!") {
+            let x = self.unary()?;
+            Ok(self.add(Operator::Not(x)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<usize, ParseError> {
+        match self.peek().clone() {
+            Token::Number(v) => {
+                self.next();
+                Ok(self.add(Operator::Value(v)))
+            },
+            Token::Symbol("(") => {
+                self.next();
+                let x = self.infix()?;
+                self.expect(")")?;
+                Ok(x)
+            },
+            Token::Name(ref name) if name == "input" => {
+                self.next();
+                self.expect("[")?;
+                let i = self.input()?;
+                self.expect("]")?;
+                Ok(i)
+            },
+            Token::Name(ref name) if name == "ambiguous" => {
+                self.next();
+                self.expect("(")?;
+                let c = self.categorical(true)?;
+                self.expect(")")?;
+                Ok(self.add(Operator::Ambiguity(c)))
+            },
+            Token::Name(ref name) if name == "if" => {
+                self.next();
+                let x = self.infix()?;
+                self.expect("{")?;
+                let y = self.infix()?;
+                self.expect("}")?;
+                match *self.peek() {
+                    Token::Name(ref name) if name == "else" => self.next(),
+                    _ => return Err(self.error("expected `else`")),
+                };
+                self.expect("{")?;
+                let z = self.infix()?;
+                self.expect("}")?;
+                Ok(self.add(Operator::Ite(x, y, z)))
+            },
+            _ => Err(self.error("expected an expression")),
+        }
+    }
+
+    fn functional(&mut self) -> Result<usize, ParseError> {
+        let name = match self.peek().clone() {
+            Token::Name(name) => {
+                self.next();
+                name
+            },
+            _ => return Err(self.error("expected an operator")),
+        };
+        self.expect("(")?;
+        let x = match name.as_ref() {
+            "Initial" => self.input()?,
+            "Value" => {
+                let v = self.number()?;
+                self.add(Operator::Value(v))
+            },
+            "Ambiguity" => {
+                let c = self.categorical(true)?;
+                self.add(Operator::Ambiguity(c))
+            },
+            _ => {
+                let mut args = vec![self.functional()?];
+                while self.eat(",") {
+                    args.push(self.functional()?);
+                }
+                let operator = self.operator(&name, &args)?;
+                self.add(operator)
+            },
+        };
+        self.expect(")")?;
+        Ok(x)
+    }
+
+    fn s_expression(&mut self) -> Result<usize, ParseError> {
+        if let Token::Number(v) = *self.peek() {
+            self.next();
+            return Ok(self.add(Operator::Value(v)));
+        }
+        self.expect("(")?;
+        let head = match self.peek().clone() {
+            Token::Name(name) => {
+                self.next();
+                name
+            },
+            _ => return Err(self.error("expected an operator")),
+        };
+        let x = match head.as_ref() {
+            "input" => self.input()?,
+            "ambiguous" => {
+                let c = self.categorical(false)?;
+                self.add(Operator::Ambiguity(c))
+            },
+            _ => {
+                let mut args = Vec::new();
+                while self.peek() != &Token::Symbol(")") && self.peek() != &Token::End {
+                    args.push(self.s_expression()?);
+                }
+                let name = match head.as_ref() {
+                    "eq" => "Equality",
+                    "inc" => "Increment",
+                    "and" => "And",
+                    "or" => "Or",
+                    "not" => "Not",
+                    "ite" => "Ite",
+                    _ => head.as_ref(),
+                };
+                let operator = self.operator(name, &args)?;
+                self.add(operator)
+            },
+        };
+        self.expect(")")?;
+        Ok(x)
+    }
+
+    /// Builds the operator with the given name, checking the number of arguments.
+    fn operator(&self, name: &str, args: &[usize]) -> Result<Operator, ParseError> {
+        let operator = match (name, args.len()) {
+            ("Equality", 2) => Operator::Equality(args[0], args[1]),
+            ("Increment", 1) => Operator::Increment(args[0]),
+            ("And", 2) => Operator::And(args[0], args[1]),
+            ("Or", 2) => Operator::Or(args[0], args[1]),
+            ("Not", 1) => Operator::Not(args[0]),
+            ("Ite", 3) => Operator::Ite(args[0], args[1], args[2]),
+            ("Equality", _) | ("Increment", _) | ("And", _) | ("Or", _) | ("Not", _) | ("Ite", _) => {
+                return Err(self.error(&format!("wrong number of arguments to `{}`", name)));
+            },
+            _ => return Err(self.error(&format!("unknown operator `{}`", name))),
+        };
+        Ok(operator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, XorShiftRng};
+    use super::*;
+
+    #[test]
+    fn it_parses_infix() {
+        use super::super::operator::Operator::*;
+        let program = parse_program("if input[1] == 2 { !input[0] } else { 1 + 1 + input[0] }", Style::Infix, 2).unwrap();
+        assert_eq!(program.operators(), &[Initial, Initial, Value(2), Equality(1, 2), Not(0), Increment(0),
+                                          Increment(5), Ite(3, 4, 6)]);
+        let program = parse_program("input[0] && input[1] || (input[0] || input[1]) && input[0]", Style::Infix, 2).unwrap();
+        assert_eq!(program.operators(), &[Initial, Initial, And(0, 1), Or(0, 1), And(3, 0), Or(2, 4)]);
+    }
+
+    #[test]
+    fn it_shares_identical_subexpressions() {
+        use super::super::operator::Operator::*;
+        let program = parse_program("(eq (not (input 0)) (not (input 0)))", Style::SExpression, 1).unwrap();
+        assert_eq!(program.operators(), &[Initial, Not(0), Equality(1, 1)]);
+        let program = parse_program("Equality(Ambiguity(0.5, 0.5), Ambiguity(0.5, 0.5))", Style::Functional, 1).unwrap();
+        assert_eq!(program.operators(), &[Initial, Ambiguity(Categorical::COIN), Ambiguity(Categorical::COIN), Equality(1, 2)]);
+        let program = parse_program("(ambiguous 0.25 0 0.75)", Style::SExpression, 0).unwrap();
+        assert_eq!(program.operators(), &[Ambiguity(Categorical::new(&[1.0, 0.0, 3.0]).unwrap())]);
+    }
+
+    #[test]
+    fn it_reports_columns() {
+        let error = |source: &str, style: Style| parse_program(source, style, 2).unwrap_err();
+        assert_eq!(error("input[0] == ", Style::Infix),
+                   ParseError { column: 13, message: "expected an expression, found the end of the program".to_owned() });
+        assert_eq!(error("if input[0] { 1 } { 0 }", Style::Infix).column, 19);
+        assert_eq!(error("input[0] == input[1] == 1", Style::Infix).column, 22);
+        assert_eq!(error("input[2]", Style::Infix).to_string(), "column 7: input 2 does not exist");
+        assert_eq!(error("2 + input[0]", Style::Infix).column, 1);
+        assert_eq!(error("input[0] ? 1", Style::Infix).column, 10);
+        assert_eq!(error("(not (input 0) 1)", Style::SExpression).column, 17);
+        assert_eq!(error("Ite(Value(1), Value(0))", Style::Functional).column, 23);
+        assert_eq!(error("(input 0))", Style::SExpression).column, 10);
+        assert_eq!(error("ambiguous(0, 0.0)", Style::Infix).column, 11);
+        assert_eq!(error("(ambiguous 0.5 x)", Style::SExpression).column, 16);
+        assert_eq!(error("if input[0] { 1 } 0", Style::Infix).column, 19);
+        assert_eq!(error("Ite(Value(1), 0)", Style::Functional).column, 15);
+        assert_eq!(error("(1)", Style::SExpression).column, 2);
+    }
+
+    #[test]
+    fn it_reports_unexpected_ends() {
+        let end = |source: &str, style: Style| {
+            let error = parse_program(source, style, 2).unwrap_err();
+            assert_eq!(error.column, source.len() + 1, "{:?}", error);
+            assert!(error.message.ends_with("found the end of the program"), "{:?}", error);
+        };
+        for &style in &[Style::Functional, Style::Infix, Style::SExpression] {
+            end("", style);
+        }
+        end("Ite(Value(1), ", Style::Functional);
+        end("Ite(Value(1), Not(", Style::Functional);
+        end("Ite(Value(1), Not(Initial(0))", Style::Functional);
+        end("input[0] && (", Style::Infix);
+        end("if input[0] {", Style::Infix);
+        end("if input[0] { 1 }", Style::Infix);
+        end("(not", Style::SExpression);
+        end("(ite (input 0) 1", Style::SExpression);
+        end("(", Style::SExpression);
+    }
+
+    #[test]
+    fn it_round_trips_through_the_printer() {
+        let mut rand_gen = XorShiftRng::from_seed([2, 4, 6, 8]);
+        let input_size = 3;
+        for _ in 0..500 {
+            let mut operators = vec![Operator::Initial; input_size];
+            for i in input_size..12 {
+                operators.push(Operator::new_rand(&mut rand_gen, i));
+            }
+            let program = Program::extract(&operators, input_size, operators.len() - 1).unwrap();
+            for &style in &[Style::Functional, Style::Infix, Style::SExpression] {
+                let text = program.display(style).to_string();
+                let parsed = parse_program(&text, style, input_size).unwrap_or_else(|e| panic!("{} in {}", e, text));
+                assert_eq!(parsed.display(style).to_string(), text);
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Stores a single program separately from the population it was evolved in.
+//!
+//! In an `Evolver`, programs only exist as indices into the shared operator array. A `Program`
+//! holds just the operators reachable from one computed, renumbered densely but in the same
+//! order, so that shared subexpressions remain shared.
+use std::fmt;
+use super::error::{Error, Result};
+use super::format::{Formatted, Style};
+use super::operator::{Operator};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    operators: Vec<Operator>,
+    input_size: usize,
+    root: usize,
+}
+
+impl Program {
+    /// Creates a program from a list of operators, whose result is the last operator. The first
+    /// `input_size` operators must be `Initial`, and every other operator may only refer to
+    /// operators before it.
+    pub fn new(operators: Vec<Operator>, input_size: usize) -> Result<Self> {
+        let root = operators.len().checked_sub(1).ok_or(Error::EmptyProgram)?;
+        Program::with_root(operators, input_size, root)
+    }
+
+    /// Creates a program as `new` does, whose result is operator `root` instead of the last.
+    pub fn with_root(operators: Vec<Operator>, input_size: usize, root: usize) -> Result<Self> {
+        if operators.is_empty() {
+            return Err(Error::EmptyProgram);
+        }
+        for (i, operator) in operators.iter().enumerate() {
+            if (i < input_size) != (*operator == Operator::Initial) {
+                return Err(Error::MisplacedInitial { computed: i });
+            }
+            for dep in operator.dependents().iter() {
+                if let Some(x) = *dep {
+                    if x >= i {
+                        return Err(Error::ForwardReference { computed: i, dependent: x });
+                    }
+                }
+            }
+        }
+        if operators.len() < input_size {
+            return Err(Error::MisplacedInitial { computed: operators.len() });
+        }
+        if root >= operators.len() {
+            return Err(Error::NoSuchComputed { computed: root, count: operators.len() });
+        }
+        Ok(Program {
+            operators,
+            input_size,
+            root,
+        })
+    }
+
+    /// Extracts the program computing `computed` from a population. Operators after the first
+    /// `input_size` may not be `Initial`, and may only refer to operators before them.
+    pub fn extract(operators: &[Operator], input_size: usize, computed: usize) -> Result<Self> {
+        if computed >= operators.len() {
+            return Err(Error::NoSuchComputed { computed, count: operators.len() });
+        }
+        for (i, operator) in operators.iter().enumerate().take(computed + 1) {
+            if let Some(&Some(x)) = operator.dependents().iter().find(|dep| dep.is_some_and(|x| x >= i)) {
+                return Err(Error::ForwardReference { computed: i, dependent: x });
+            }
+        }
+        let mut relocations: Vec<Option<usize>> = vec![None; computed + 1];
+        let mut program: Vec<Operator> = vec![Operator::Initial; input_size];
+        for (i, relocation) in relocations.iter_mut().enumerate().take(input_size) {
+            *relocation = Some(i);
+        }
+        for i in reachable(operators, computed) {
+            if i >= input_size {
+                if operators[i] == Operator::Initial {
+                    return Err(Error::MisplacedInitial { computed: i });
+                }
+                relocations[i] = Some(program.len());
+                program.push(operators[i].relocate(&relocations)?);
+            }
+        }
+        // The root is an input, or else the last operator relocated.
+        let root = if computed < input_size { computed } else { program.len() - 1 };
+        Ok(Program {
+            operators: program,
+            input_size,
+            root,
+        })
+    }
+
+    pub fn operators(&self) -> &[Operator] {
+        &self.operators
+    }
+
+    pub fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    /// The index of the operator computing the result of the program.
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    /// The number of operators, including the inputs.
+    pub fn len(&self) -> usize {
+        self.operators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operators.is_empty()
+    }
+
+    /// Displays the program in a given style. `Display` uses `Style::Infix`.
+    pub fn display(&self, style: Style) -> Formatted<'_> {
+        Formatted::new(&self.operators, self.root, style)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(Style::Infix).fmt(f)
+    }
+}
+
+/// Returns the computed a computed depends on, including itself, in increasing order.
+pub fn reachable(operators: &[Operator], computed: usize) -> Vec<usize> {
+    let mut reachable = vec![false; computed + 1];
+    reachable[computed] = true;
+    for i in (0..(computed + 1)).rev() {
+        if reachable[i] {
+            for dep in operators[i].dependents().iter() {
+                if let Some(x) = *dep {
+                    reachable[x] = true;
+                }
+            }
+        }
+    }
+    (0..(computed + 1)).filter(|&i| reachable[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_extracts_shared_subexpressions_once() {
+        let operators = [Operator::Initial, Operator::Initial, Operator::Value(2), Operator::Equality(0, 1),
+                         Operator::Not(2), Operator::Not(3), Operator::And(3, 5), Operator::Value(1)];
+        let program = Program::extract(&operators, 2, 6).unwrap();
+        assert_eq!(program.operators(), &[Operator::Initial, Operator::Initial, Operator::Equality(0, 1),
+                                          Operator::Not(2), Operator::And(2, 3)]);
+        assert_eq!(program.root(), 4);
+        assert_eq!(program, Program::extract(program.operators(), 2, 4).unwrap());
+    }
+
+    #[test]
+    fn it_extracts_inputs() {
+        let operators = [Operator::Initial, Operator::Initial, Operator::Value(2)];
+        let program = Program::extract(&operators, 2, 1).unwrap();
+        assert_eq!(program.operators(), &[Operator::Initial, Operator::Initial]);
+        assert_eq!(program.root(), 1);
+    }
+
+    #[test]
+    fn it_displays_the_root() {
+        let operators = vec![Operator::Initial, Operator::Not(0), Operator::Value(1)];
+        let program = Program::extract(&operators, 1, 1).unwrap();
+        assert_eq!(program.to_string(), "!input[0]");
+        assert_eq!(program.display(Style::SExpression).to_string(), "(not (input 0))");
+    }
+
+    #[test]
+    fn it_reports_malformed_populations() {
+        let operators = [Operator::Initial, Operator::Not(0), Operator::Initial, Operator::And(1, 2),
+                         Operator::Not(5)];
+        assert_eq!(Program::extract(&operators, 1, 5), Err(Error::NoSuchComputed { computed: 5, count: 5 }));
+        assert_eq!(Program::extract(&operators, 1, 3), Err(Error::MisplacedInitial { computed: 2 }));
+        assert_eq!(Program::extract(&operators, 1, 4), Err(Error::ForwardReference { computed: 4, dependent: 5 }));
+        assert_eq!(Program::extract(&operators, 1, 1).unwrap().to_string(), "!input[0]");
+    }
+}
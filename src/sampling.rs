@@ -0,0 +1,83 @@
+//! Estimates computed distributions by executing programs on concrete values.
+//!
+//! This is option 1 from `doc/arb.md`. Each trace draws the inputs from their distributions, then
+//! runs every operator in order on the concrete values produced by the trace so far. Since all
+//! computed in a trace see the same random choices, correlations between computed which share a
+//! random ancestor are preserved, unlike in the analytic evaluation.
+use rand;
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::operator::{Operator};
+
+/// Replaces the distributions of all non-input computed with the empirical distribution of
+/// `traces` sampled traces.
+pub fn sample_distributions<P, R>(operators: &[Operator], input_size: usize, dists: &mut ComputedDistributions<P>,
+                                  traces: usize, rand_gen: &mut R) where P: Probability, R: rand::Rng {
+    let size = dists.size();
+    let mut counts = vec![0usize; (size + 1) * operators.len()];
+    let mut values = vec![0; operators.len()];
+    for _ in 0..traces {
+        for (i, value) in values.iter_mut().enumerate().take(input_size) {
+            *value = sample_value(dists.read(i), rand_gen);
+        }
+        for i in input_size..operators.len() {
+            let mut args = [None; 3];
+            for (arg, dep) in args.iter_mut().zip(operators[i].dependents().iter()) {
+                *arg = dep.map(|x| values[x]);
+            }
+            values[i] = operators[i].exec_in_domain(args, size, rand_gen);
+        }
+        for (i, &value) in values.iter().enumerate().skip(input_size) {
+            counts[i * (size + 1) + value] += 1;
+        }
+    }
+    let denom = usize::max(1, traces) as f64;
+    for i in input_size..operators.len() {
+        let row = &counts[i * (size + 1)..(i + 1) * (size + 1)];
+        for (p, &count) in dists.read_mut(i).iter_mut().zip(row.iter()) {
+            *p = P::from_f64(count as f64 / denom);
+        }
+    }
+}
+
+/// Draws a value (possibly the undefined value) from a distribution.
+fn sample_value<P, R>(distribution: &[P], rand_gen: &mut R) -> usize where P: Probability, R: rand::Rng {
+    let mut remaining = rand_gen.gen::<f64>();
+    for (v, pv) in distribution.iter().map(|p| p.to_f64()).enumerate() {
+        if remaining < pv {
+            return v;
+        }
+        remaining -= pv;
+    }
+    distribution.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, XorShiftRng};
+    use super::*;
+    use super::super::operator::{Categorical};
+
+    #[test]
+    fn it_samples_deterministic_programs_exactly() {
+        let operators = [Operator::Initial, Operator::Initial, Operator::Equality(0, 1), Operator::Increment(0),
+                         Operator::Increment(3)];
+        let mut dists = ComputedDistributions::new(3, operators.len());
+        dists.set_values(0, &[1, 1]).unwrap();
+        let mut rand_gen = XorShiftRng::from_seed([1, 2, 3, 4]);
+        sample_distributions(&operators, 2, &mut dists, 16, &mut rand_gen);
+        assert_eq!(dists.read(2), &[0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(dists.read(3), &[0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(dists.read(4), &[0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn it_preserves_correlations() {
+        let operators = [Operator::Initial, Operator::Ambiguity(Categorical::COIN), Operator::Equality(1, 1)];
+        let mut dists = ComputedDistributions::new(2, operators.len());
+        dists.set_values(0, &[0]).unwrap();
+        let mut rand_gen = XorShiftRng::from_seed([1, 2, 3, 4]);
+        sample_distributions(&operators, 1, &mut dists, 1000, &mut rand_gen);
+        assert!((dists.read(1)[0] - 0.5).abs() < 0.1);
+        assert_eq!(dists.read(2), &[0.0, 1.0, 0.0]);
+    }
+}
@@ -1,7 +1,10 @@
-use super::computed_distributions::{ComputedDistributions};
+use super::computed_distributions::{ComputedDistributions, Probability};
+use super::error::{Error, Result};
 use super::operator::{Operator};
+use super::program::{reachable};
 
-fn portion_correct_score(computed: usize, output: usize, dists: &[ComputedDistributions], targets: &[Vec<usize>]) -> f32 {
+pub fn portion_correct_score<P>(computed: usize, output: usize, dists: &[ComputedDistributions<P>], targets: &[Vec<usize>]) -> f32
+    where P: Probability {
     let mut num_wrong = 0;
     for (d, t) in dists.iter().zip(targets.iter()) {
         let (v, _) = d.read_likely(computed);
@@ -13,28 +16,111 @@ fn portion_correct_score(computed: usize, output: usize, dists: &[ComputedDistri
     1.0 - ((num_wrong as f64) / denom) as f32
 }
 
-fn total_complexity(computed: usize, operators: &[Operator]) -> usize {
-    let mut total = 1;
-    for dep in operators[computed].dependents().iter() {
-        if let &Some(x) = dep {
-            total += total_complexity(x, operators);
-        }
+/// How the complexity of a computed is measured. Every measure counts inputs as 1, except
+/// `Weighted`, which uses the cost of `Initial`.
+///
+/// `TreeSize` and `Depth` take linear time to measure a whole population, but `DagNodes` and
+/// `Weighted` track the set of operators each computed depends on, which takes time and memory
+/// quadratic in the size of the population.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Complexity {
+    /// The number of distinct operators the computed depends on, including itself.
+    DagNodes,
+    /// The number of operators if shared subexpressions were copied for every use. Saturates at
+    /// `usize::MAX`.
+    #[default]
+    TreeSize,
+    /// The number of operators on the longest path from the computed to an input.
+    Depth,
+    /// The total cost of the distinct operators the computed depends on.
+    Weighted(fn(Operator) -> usize),
+}
+
+/// The tree size of a computed.
+pub fn total_complexity(computed: usize, operators: &[Operator]) -> usize {
+    complexity(operators, computed, Complexity::TreeSize)
+}
+
+/// Measures the complexity of a single computed, visiting each operator it depends on once.
+pub fn complexity(operators: &[Operator], computed: usize, measure: Complexity) -> usize {
+    let nodes = reachable(operators, computed);
+    match measure {
+        Complexity::DagNodes => nodes.len(),
+        Complexity::Weighted(cost) => nodes.iter().map(|&i| cost(operators[i])).sum(),
+        Complexity::TreeSize | Complexity::Depth => {
+            let mut memo = vec![0; computed + 1];
+            for &i in nodes.iter() {
+                memo[i] = combine_dependents(operators[i], &memo, measure);
+            }
+            memo[computed]
+        },
+    }
+}
+
+/// Measures the complexity of every computed in one pass over the operators.
+pub fn complexities(operators: &[Operator], measure: Complexity) -> Vec<usize> {
+    let mut memo = vec![0; operators.len()];
+    match measure {
+        Complexity::TreeSize | Complexity::Depth => {
+            for (i, &operator) in operators.iter().enumerate() {
+                memo[i] = combine_dependents(operator, &memo, measure);
+            }
+        },
+        Complexity::DagNodes | Complexity::Weighted(_) => {
+            // The set of operators each computed depends on, as a bitset over earlier computed.
+            let words = operators.len().div_ceil(64);
+            let mut sets: Vec<u64> = vec![0; words * operators.len()];
+            for (i, &operator) in operators.iter().enumerate() {
+                let (earlier, rest) = sets.split_at_mut(i * words);
+                let set = &mut rest[..words];
+                set[i / 64] |= 1 << (i % 64);
+                for x in operator.dependents().iter().filter_map(|&d| d) {
+                    for (word, dep_word) in set.iter_mut().zip(earlier[x * words..(x + 1) * words].iter()) {
+                        *word |= *dep_word;
+                    }
+                }
+                memo[i] = match measure {
+                    Complexity::Weighted(cost) => set.iter().enumerate().map(|(w, &word)| {
+                        // Visit only the set bits of each word.
+                        let mut bits = word;
+                        let mut total = 0;
+                        while bits != 0 {
+                            total += cost(operators[w * 64 + bits.trailing_zeros() as usize]);
+                            bits &= bits - 1;
+                        }
+                        total
+                    }).sum(),
+                    _ => set.iter().map(|word| word.count_ones() as usize).sum(),
+                };
+            }
+        },
+    }
+    memo
+}
+
+/// Combines the memoized tree sizes or depths of the dependents of an operator.
+fn combine_dependents(operator: Operator, memo: &[usize], measure: Complexity) -> usize {
+    let deps = operator.dependents();
+    let deps = deps.iter().filter_map(|&d| d).map(|x| memo[x]);
+    match measure {
+        Complexity::Depth => 1 + deps.max().unwrap_or(0),
+        _ => deps.fold(1, usize::saturating_add),
     }
-    total
 }
 
 fn infinite_to_1(x: f32) -> f32 {
     x / (1.0 + x)
 }
 
-fn complexity_score(computed: usize, operators: &[Operator]) -> f32 {
-    1.0 - infinite_to_1(total_complexity(computed, operators) as f32)
+pub fn complexity_score(complexity: usize) -> f32 {
+    1.0 - infinite_to_1(complexity as f32)
 }
 
-fn log_mse_score(computed: usize, output: usize, dists: &[ComputedDistributions], targets: &[Vec<usize>]) -> f32 {
+pub fn log_mse_score<P>(computed: usize, output: usize, dists: &[ComputedDistributions<P>], targets: &[Vec<usize>]) -> f32
+    where P: Probability {
     let mut error = 0.0;
     for (d, t) in dists.iter().zip(targets.iter()) {
-        for (v, pv) in d.read(computed).iter().enumerate() {
+        for (v, pv) in d.read(computed).iter().map(|p| p.to_f64() as f32).enumerate() {
             let err = t[output] as i32 - v as i32;
             error += pv * pv * (err * err) as f32;
         }
@@ -42,48 +128,261 @@ fn log_mse_score(computed: usize, output: usize, dists: &[ComputedDistributions]
     1.0 - infinite_to_1(error)
 }
 
-fn combine_scores(old_score: f32, new_score: f32) -> f32 {
-    f32::max(old_score, new_score)
+/// How much credit a computed receives from the computed which use it, when scores are
+/// propagated.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Credit {
+    /// The best score of any computed which depends on it, including itself.
+    #[default]
+    Max,
+    /// Its own score plus the credit of every operator which uses it, counting each operator
+    /// once, even if it uses the computed more than once.
+    Sum,
+    /// Like `Max`, but the credit from a computed `d` operators away is multiplied by
+    /// `factor` to the power `d`.
+    Decayed { factor: f32 },
 }
 
-pub fn compute_score_for_output(dists: &[ComputedDistributions], computed: usize, output: usize, targets: &[Vec<usize>], operators: &[Operator]) -> f32 {
+pub fn compute_score_for_output<P>(dists: &[ComputedDistributions<P>], computed: usize, output: usize, targets: &[Vec<usize>], complexity: usize) -> f32
+    where P: Probability {
     10.0 * portion_correct_score(computed, output, dists, targets) +
         5.0 * log_mse_score(computed, output, dists, targets) +
-        complexity_score(computed, operators)
+        complexity_score(complexity)
 }
 
-fn propagate_score(operators: &[Operator], scores: &mut [f32], i: usize, score: f32) {
-    scores[i] = combine_scores(scores[i], score);
-    for dep in operators[i].dependents().iter() {
-        if let &Some(x) = dep {
-            propagate_score(operators, scores, x, score);
+/// Gives each computed credit for the scores of the computed which use it, given the score of
+/// every computed on its own. Since operators only use earlier computed, one sweep from the end
+/// of the population visits every user of a computed before the computed itself.
+pub fn propagate_scores(operators: &[Operator], scores: &mut [f32], credit: Credit) {
+    for i in (0..operators.len()).rev() {
+        let mut deps = operators[i].dependents();
+        // Count an operator which uses the same computed twice only once.
+        if deps[1].is_some() && deps[1] == deps[0] {
+            deps[1] = None;
+        }
+        if deps[2].is_some() && (deps[2] == deps[0] || deps[2] == deps[1]) {
+            deps[2] = None;
+        }
+        for x in deps.iter().filter_map(|&d| d) {
+            scores[x] = match credit {
+                Credit::Max => f32::max(scores[x], scores[i]),
+                Credit::Sum => scores[x] + scores[i],
+                Credit::Decayed { factor } => f32::max(scores[x], factor * scores[i]),
+            };
         }
     }
 }
 
-pub fn score_values(dists: &[ComputedDistributions], i: usize, operators: &[Operator], scores: &mut [f32], targets: &[Vec<usize>]) -> (f32, usize) {
+/// Which inputs the program for an output may, and must, depend on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputMask {
+    allowed: Vec<bool>,
+    required: Vec<bool>,
+}
+
+impl InputMask {
+    /// Allows every one of `input_size` inputs, and requires none.
+    pub fn new(input_size: usize) -> Self {
+        InputMask {
+            allowed: vec![true; input_size],
+            required: vec![false; input_size],
+        }
+    }
+
+    /// Allows only the given inputs, and any required ones. Fails if an input doesn't exist.
+    pub fn allow_only(mut self, inputs: &[usize]) -> Result<Self> {
+        self.check_inputs(inputs)?;
+        for (i, allowed) in self.allowed.iter_mut().enumerate() {
+            *allowed = inputs.contains(&i) || self.required[i];
+        }
+        Ok(self)
+    }
+
+    /// Requires, and so allows, the given inputs. Fails if an input doesn't exist.
+    pub fn require(mut self, inputs: &[usize]) -> Result<Self> {
+        self.check_inputs(inputs)?;
+        for &i in inputs.iter() {
+            self.required[i] = true;
+            self.allowed[i] = true;
+        }
+        Ok(self)
+    }
+
+    fn check_inputs(&self, inputs: &[usize]) -> Result<()> {
+        let input_size = self.input_size();
+        match inputs.iter().find(|&&input| input >= input_size) {
+            Some(&input) => Err(Error::NoSuchInput { input, input_size }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn input_size(&self) -> usize {
+        self.allowed.len()
+    }
+
+    /// Whether a computed depending on the inputs in `uses` may be a program for the output.
+    pub fn admits(&self, uses: &[bool]) -> bool {
+        uses.iter().zip(self.allowed.iter().zip(self.required.iter()))
+            .all(|(&used, (&allowed, &required))| if used { allowed } else { !required })
+    }
+}
+
+/// Finds the inputs each computed depends on, in one pass over the operators.
+pub fn input_dependencies(operators: &[Operator], input_size: usize) -> Vec<Vec<bool>> {
+    let mut uses: Vec<Vec<bool>> = Vec::with_capacity(operators.len());
+    for (i, operator) in operators.iter().enumerate() {
+        let mut used = vec![false; input_size];
+        if i < input_size {
+            used[i] = true;
+        }
+        for x in operator.dependents().iter().filter_map(|&d| d) {
+            for (used, &dep_used) in used.iter_mut().zip(uses[x].iter()) {
+                *used |= dep_used;
+            }
+        }
+        uses.push(used);
+    }
+    uses
+}
+
+/// Scores a computed as a predictor of each output which `admits` it, such as those whose mask
+/// admits the inputs it uses, returning the best score and that output. A computed which no
+/// output admits scores 0.
+pub fn score_values<P, F>(dists: &[ComputedDistributions<P>], i: usize, complexity: usize, targets: &[Vec<usize>],
+                          admits: F) -> (f32, usize)
+    where P: Probability, F: Fn(usize) -> bool {
     let mut best_score = -1e9;
     let mut output = 0;
     for output_idx in 0..targets[0].len() {
-        let score = compute_score_for_output(dists, i, output_idx, targets, operators);
+        if !admits(output_idx) {
+            continue;
+        }
+        let score = compute_score_for_output(dists, i, output_idx, targets, complexity);
         if score > best_score {
             best_score = score;
             output = output_idx;
         }
     }
-    propagate_score(operators, scores, i, best_score);
+    if best_score < 0.0 {
+        best_score = 0.0;
+    }
     (best_score, output)
 }
 
 #[cfg(test)]
 #[test]
 fn it_scores_portions_correct() {
-    assert_eq!(portion_correct_score(0, 0, &[], &[]), 1.0);
+    assert_eq!(portion_correct_score::<f32>(0, 0, &[], &[]), 1.0);
     let mut dists = ComputedDistributions::new(3, 2);
-    dists.set_values(0, &[1, 2]);
+    dists.set_values(0, &[1, 2]).unwrap();
     let d = &[dists.clone(), dists.clone()];
     assert_eq!(portion_correct_score(0, 0, &[dists.clone()], &[vec![1]]), 1.0);
     assert_eq!(portion_correct_score(0, 0, d, &[vec![1, 2], vec![1, 2]]), 1.0);
     assert_eq!(portion_correct_score(1, 1, d, &[vec![1, 2], vec![1, 2]]), 1.0);
     assert_eq!(portion_correct_score(0, 0, d, &[vec![1, 2], vec![0, 2]]), 0.5);
 }
+
+#[cfg(test)]
+#[test]
+fn it_measures_shared_subexpressions() {
+    // Each And uses the previous one twice, so the tree doubles in size at every level.
+    let mut operators = vec![Operator::Initial];
+    for i in 0..80 {
+        operators.push(Operator::And(i, i));
+    }
+    let cost = |operator: Operator| if operator == Operator::Initial { 0 } else { 2 };
+    assert_eq!(complexity(&operators, 80, Complexity::DagNodes), 81);
+    assert_eq!(complexity(&operators, 80, Complexity::Depth), 81);
+    assert_eq!(complexity(&operators, 80, Complexity::Weighted(cost)), 160);
+    assert_eq!(complexity(&operators, 10, Complexity::TreeSize), 2047);
+    assert_eq!(total_complexity(80, &operators), usize::MAX);
+}
+
+#[cfg(test)]
+#[test]
+fn it_measures_the_whole_population_at_once() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rand_gen = XorShiftRng::from_seed([1, 3, 5, 7]);
+    let mut operators = vec![Operator::Initial; 3];
+    for i in 3..200 {
+        operators.push(Operator::new_rand(&mut rand_gen, i));
+    }
+    let cost = |operator: Operator| operator.name().len();
+    for &measure in &[Complexity::DagNodes, Complexity::TreeSize, Complexity::Depth, Complexity::Weighted(cost)] {
+        let all = complexities(&operators, measure);
+        for (i, &c) in all.iter().enumerate() {
+            assert_eq!(c, complexity(&operators, i, measure), "{:?} of {}", measure, i);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn it_propagates_scores_to_building_blocks() {
+    use super::operator::Operator::*;
+    // 3 uses 1 twice, 4 uses 3 and 2, and 5 uses 3.
+    let operators = [Initial, Value(1), Value(0), Not(1), Ite(3, 3, 2), And(3, 3)];
+    let own = [0.0, 1.0, 2.0, 3.0, 8.0, 4.0];
+    let propagated = |credit| {
+        let mut scores = own;
+        propagate_scores(&operators, &mut scores, credit);
+        scores
+    };
+    assert_eq!(propagated(Credit::Max), [0.0, 8.0, 8.0, 8.0, 8.0, 4.0]);
+    assert_eq!(propagated(Credit::Sum), [0.0, 16.0, 10.0, 15.0, 8.0, 4.0]);
+    assert_eq!(propagated(Credit::Decayed { factor: 0.5 }), [0.0, 2.0, 4.0, 4.0, 8.0, 4.0]);
+}
+
+#[cfg(test)]
+#[test]
+fn it_propagates_the_best_score_of_any_user() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    fn naive(operators: &[Operator], scores: &mut [f32], i: usize, score: f32) {
+        scores[i] = f32::max(scores[i], score);
+        for x in operators[i].dependents().iter().filter_map(|&d| d) {
+            naive(operators, scores, x, score);
+        }
+    }
+    let mut rand_gen = XorShiftRng::from_seed([2, 3, 5, 7]);
+    let mut operators = vec![Operator::Initial; 3];
+    for i in 3..40 {
+        operators.push(Operator::new_rand(&mut rand_gen, i));
+    }
+    let own: Vec<f32> = operators.iter().map(|_| rand_gen.gen_range(0.0, 16.0)).collect();
+    let mut expected = own.clone();
+    for (i, &score) in own.iter().enumerate() {
+        naive(&operators, &mut expected, i, score);
+    }
+    let mut scores = own.clone();
+    propagate_scores(&operators, &mut scores, Credit::Max);
+    assert_eq!(scores, expected);
+}
+
+#[cfg(test)]
+#[test]
+fn it_respects_input_masks() {
+    use super::operator::Operator::*;
+    let operators = [Initial, Initial, Initial, Not(1), Equality(0, 2), Ite(3, 4, 3)];
+    let uses = input_dependencies(&operators, 3);
+    assert_eq!(uses[5], vec![true, true, true]);
+    assert_eq!(uses[4], vec![true, false, true]);
+    let only_player = InputMask::new(3).allow_only(&[1]).unwrap();
+    assert!(only_player.admits(&uses[3]) && !only_player.admits(&uses[4]));
+    let needs_counter = InputMask::new(3).require(&[2]).unwrap();
+    assert!(!needs_counter.admits(&uses[3]) && needs_counter.admits(&uses[5]));
+    assert!(InputMask::new(3).allow_only(&[0]).and_then(|mask| mask.require(&[2])).unwrap().admits(&uses[4]));
+    assert_eq!(InputMask::new(3).allow_only(&[1, 5]), Err(Error::NoSuchInput { input: 5, input_size: 3 }));
+    assert_eq!(InputMask::new(3).require(&[3]), Err(Error::NoSuchInput { input: 3, input_size: 3 }));
+
+    let mut dists = ComputedDistributions::new(3, 6);
+    dists.set_values(0, &[2, 0, 2]).unwrap();
+    for (i, operator) in operators.iter().enumerate().skip(3) {
+        operator.run(i, &mut dists).unwrap();
+    }
+    // Not(player) predicts the next player perfectly, unless the mask rules it out.
+    let targets = [vec![2, 1, 2]];
+    let dists = [dists];
+    assert_eq!(score_values(&dists, 3, 2, &targets, |_| true).1, 1);
+    assert_eq!(score_values(&dists, 3, 2, &targets, |output| output != 1 || needs_counter.admits(&uses[3])).1, 0);
+    assert_eq!(score_values(&dists, 3, 2, &targets, |_| needs_counter.admits(&uses[3])), (0.0, 0));
+}
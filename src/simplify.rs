@@ -0,0 +1,289 @@
+//! Simplifies programs by rewriting their operators.
+//!
+//! The rewrites preserve the exact semantics of programs over a domain of `size` values,
+//! including when values are undefined, and treat each `Ambiguity` as a separate random choice.
+//! Because the analytic evaluation ignores correlations, simplification can change the
+//! analytically computed distributions of programs which use the same random value twice.
+use std::collections::{HashMap};
+use rand;
+use super::operator::{Operator};
+use super::program::{Program};
+
+/// What is known about the values of a computed.
+#[derive(Clone, Copy, Debug)]
+struct Facts {
+    /// The computed always has this value, where `size` is undefined.
+    constant: Option<usize>,
+    /// The computed is never undefined.
+    total: bool,
+    /// The computed is always 0, 1, or undefined, and 1 is in the domain.
+    boolean: bool,
+}
+
+enum Rewrite {
+    /// The operator computes the same thing as an existing computed.
+    Existing(usize),
+    /// The operator computes the same thing as a simpler operator.
+    Simpler(Operator),
+    Unchanged,
+}
+
+/// Simplifies a program over a domain of `size` values. Shared subexpressions, including ones
+/// which only become identical after simplification, are computed once.
+pub fn simplify(program: &Program, size: usize) -> Program {
+    let input_size = program.input_size();
+    let mut simplifier = Simplifier {
+        size,
+        operators: Vec::with_capacity(program.len()),
+        facts: Vec::with_capacity(program.len()),
+        interned: HashMap::new(),
+    };
+    let mut relocations: Vec<Option<usize>> = Vec::with_capacity(program.len());
+    for i in 0..input_size {
+        simplifier.operators.push(Operator::Initial);
+        simplifier.facts.push(Facts { constant: None, total: true, boolean: false });
+        relocations.push(Some(i));
+    }
+    for operator in program.operators().iter().skip(input_size) {
+        let relocated = simplifier.add(operator.relocate(&relocations).expect("dependents are simplified first"));
+        relocations.push(Some(relocated));
+    }
+    let root = relocations[program.root()].expect("the root should have been simplified");
+    Program::extract(&simplifier.operators, input_size, root).expect("simplified operators are well formed")
+}
+
+struct Simplifier {
+    size: usize,
+    operators: Vec<Operator>,
+    facts: Vec<Facts>,
+    interned: HashMap<Operator, usize>,
+}
+
+impl Simplifier {
+    /// Adds a simplified version of an operator, returning its index.
+    fn add(&mut self, operator: Operator) -> usize {
+        let mut operator = canonicalize(operator);
+        loop {
+            match self.rewrite(operator) {
+                Rewrite::Existing(x) => return x,
+                Rewrite::Simpler(simpler) => operator = canonicalize(simpler),
+                Rewrite::Unchanged => break,
+            }
+        }
+        if let Operator::Ambiguity(_) = operator {
+            // Each Ambiguity is a separate random choice, so they can't be shared.
+        } else if let Some(&existing) = self.interned.get(&operator) {
+            return existing;
+        }
+        let facts = self.facts_for(operator);
+        self.operators.push(operator);
+        self.facts.push(facts);
+        self.interned.insert(operator, self.operators.len() - 1);
+        self.operators.len() - 1
+    }
+
+    fn rewrite(&self, operator: Operator) -> Rewrite {
+        let size = self.size;
+        let constant = |x: usize| self.facts[x].constant;
+        let total = |x: usize| self.facts[x].total;
+        let boolean = |x: usize| self.facts[x].boolean;
+        let is = |x: usize, v: usize| constant(x) == Some(v);
+        let nonzero = |x: usize| constant(x).is_some_and(|v| v != 0 && v < size);
+        let deps = operator.dependents();
+        if let Operator::Value(v) = operator {
+            if v > size {
+                // All values outside of the domain are undefined.
+                return Rewrite::Simpler(Operator::Value(size));
+            }
+        }
+        match operator {
+            // A choice with only one possible value isn't random.
+            Operator::Ambiguity(c) if c.constant().is_some() =>
+                return Rewrite::Simpler(Operator::Value(c.constant().expect("checked above"))),
+            Operator::Initial | Operator::Value(_) | Operator::Ambiguity(_) => {},
+            _ if deps.iter().all(|d| d.is_none_or(|x| constant(x).is_some())) => {
+                let mut args = [None; 3];
+                for (arg, dep) in args.iter_mut().zip(deps.iter()) {
+                    *arg = dep.and_then(constant);
+                }
+                // No random choices are made, since the operator has inputs.
+                let value = operator.exec_in_domain(args, size, &mut rand::XorShiftRng::new_unseeded());
+                return Rewrite::Simpler(Operator::Value(value));
+            },
+            _ => {},
+        }
+        match operator {
+            Operator::Not(x) => match self.operators[x] {
+                Operator::Not(y) if boolean(y) => Rewrite::Existing(y),
+                _ => Rewrite::Unchanged,
+            },
+            Operator::Equality(x, y) if x == y && total(x) => Rewrite::Simpler(Operator::Value(1)),
+            Operator::And(x, y) | Operator::Or(x, y) if x == y && boolean(x) => Rewrite::Existing(x),
+            Operator::And(x, y) if is(x, 0) && total(y) => Rewrite::Existing(x),
+            Operator::And(x, y) if is(y, 0) && total(x) => Rewrite::Existing(y),
+            Operator::And(x, y) if nonzero(x) && boolean(y) => Rewrite::Existing(y),
+            Operator::And(x, y) if nonzero(y) && boolean(x) => Rewrite::Existing(x),
+            Operator::Or(x, y) if nonzero(x) && total(y) => Rewrite::Simpler(Operator::Value(1)),
+            Operator::Or(x, y) if nonzero(y) && total(x) => Rewrite::Simpler(Operator::Value(1)),
+            Operator::Or(x, y) if is(x, 0) && boolean(y) => Rewrite::Existing(y),
+            Operator::Or(x, y) if is(y, 0) && boolean(x) => Rewrite::Existing(x),
+            Operator::Ite(x, y, z) => match constant(x) {
+                Some(c) if c >= size => Rewrite::Simpler(Operator::Value(size)),
+                Some(0) => Rewrite::Existing(z),
+                Some(_) => Rewrite::Existing(y),
+                None if y == z && total(x) => Rewrite::Existing(y),
+                None if is(y, 1) && is(z, 0) && boolean(x) => Rewrite::Existing(x),
+                None if is(y, 0) && is(z, 1) => Rewrite::Simpler(Operator::Not(x)),
+                None => match self.operators[x] {
+                    // Not(w) is undefined exactly when w is, as long as 1 is in the domain.
+                    Operator::Not(w) if size >= 2 => Rewrite::Simpler(Operator::Ite(w, z, y)),
+                    _ => Rewrite::Unchanged,
+                },
+            },
+            _ => Rewrite::Unchanged,
+        }
+    }
+
+    fn facts_for(&self, operator: Operator) -> Facts {
+        let size = self.size;
+        let has_bool = size >= 2;
+        let total = |x: usize| self.facts[x].total;
+        let boolean = |x: usize| self.facts[x].boolean;
+        match operator {
+            Operator::Initial => Facts { constant: None, total: true, boolean: false },
+            Operator::Value(v) => Facts { constant: Some(v), total: v < size, boolean: has_bool && v <= 1 },
+            Operator::Ambiguity(c) =>
+                Facts { constant: None, total: c.support() <= size, boolean: has_bool && c.support() <= 2 },
+            Operator::Increment(_) => Facts { constant: None, total: false, boolean: false },
+            Operator::Not(x) => Facts { constant: None, total: has_bool && total(x), boolean: has_bool },
+            Operator::Equality(x, y) | Operator::And(x, y) | Operator::Or(x, y) =>
+                Facts { constant: None, total: has_bool && total(x) && total(y), boolean: has_bool },
+            Operator::Ite(x, y, z) =>
+                Facts { constant: None, total: total(x) && total(y) && total(z), boolean: boolean(y) && boolean(z) },
+        }
+    }
+}
+
+/// Orders the arguments of symmetric operators, so that equivalent operators are identical.
+fn canonicalize(operator: Operator) -> Operator {
+    match operator {
+        Operator::Equality(x, y) if y < x => Operator::Equality(y, x),
+        Operator::And(x, y) if y < x => Operator::And(y, x),
+        Operator::Or(x, y) if y < x => Operator::Or(y, x),
+        _ => operator,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use super::*;
+    use super::super::computed_distributions::{ComputedDistributions};
+    use super::super::exact::{evaluate_exact};
+    use super::super::operator::{Categorical};
+
+    fn simplified(operators: Vec<Operator>, input_size: usize, size: usize) -> Vec<Operator> {
+        simplify(&Program::new(operators, input_size).unwrap(), size).operators().to_owned()
+    }
+
+    #[test]
+    fn it_simplifies_junk() {
+        use super::super::operator::Operator::*;
+        assert_eq!(simplified(vec![Initial, Equality(0, 0), Not(1), Not(2)], 1, 3), vec![Initial, Value(1)]);
+        assert_eq!(simplified(vec![Initial, Initial, Equality(0, 1), Not(2), Not(3)], 2, 3),
+                   vec![Initial, Initial, Equality(0, 1)]);
+        assert_eq!(simplified(vec![Initial, Initial, Equality(0, 1), And(2, 2)], 2, 3),
+                   vec![Initial, Initial, Equality(0, 1)]);
+        assert_eq!(simplified(vec![Initial, Initial, Value(1), Ite(2, 0, 1)], 2, 3), vec![Initial, Initial]);
+        assert_eq!(simplified(vec![Initial, Initial, Equality(0, 1), Ite(2, 1, 1)], 2, 3), vec![Initial, Initial]);
+        assert_eq!(simplified(vec![Initial, Initial, Equality(0, 1), Equality(1, 0), Or(2, 3)], 2, 3),
+                   vec![Initial, Initial, Equality(0, 1)]);
+        assert_eq!(simplified(vec![Initial, Value(2), Increment(1), Not(2)], 1, 3), vec![Initial, Value(3)]);
+    }
+
+    #[test]
+    fn it_keeps_undefined_values() {
+        use super::super::operator::Operator::*;
+        assert_eq!(simplified(vec![Initial, Increment(0), Equality(1, 1)], 1, 3),
+                   vec![Initial, Increment(0), Equality(1, 1)]);
+        assert_eq!(simplified(vec![Initial, Increment(0), Value(0), And(1, 2)], 1, 3),
+                   vec![Initial, Increment(0), Value(0), And(1, 2)]);
+    }
+
+    #[test]
+    fn it_keeps_separate_random_choices() {
+        use super::super::operator::Operator::*;
+        assert_eq!(simplified(vec![Initial, Ambiguity(Categorical::COIN), Ambiguity(Categorical::COIN), Equality(1, 2)], 1, 2),
+                   vec![Initial, Ambiguity(Categorical::COIN), Ambiguity(Categorical::COIN), Equality(1, 2)]);
+        // A choice of only one value isn't random.
+        let certain = Categorical::new(&[0.0, 1.0]).unwrap();
+        assert_eq!(simplified(vec![Initial, Ambiguity(certain), Not(1)], 1, 2), vec![Initial, Value(0)]);
+    }
+
+    fn exact_root(program: &Program, size: usize, inputs: &[usize]) -> Vec<f32> {
+        let mut dists = ComputedDistributions::new(size, program.len());
+        dists.set_values(0, inputs).unwrap();
+        evaluate_exact(program.operators(), program.input_size(), program.input_size(), &mut dists, 64).unwrap();
+        dists.read(program.root()).to_owned()
+    }
+
+    fn analytic_root(program: &Program, size: usize, inputs: &[usize]) -> Vec<f32> {
+        let mut dists = ComputedDistributions::new(size, program.len());
+        dists.set_values(0, inputs).unwrap();
+        for (i, operator) in program.operators().iter().enumerate().skip(program.input_size()) {
+            operator.run(i, &mut dists).unwrap();
+        }
+        dists.read(program.root()).to_owned()
+    }
+
+    /// Whether the root reaches some `Ambiguity` along more than one path, so that the analytic
+    /// evaluation, which ignores correlations, may differ from the exact one.
+    fn reuses_ambiguity(program: &Program) -> bool {
+        let operators = program.operators();
+        let mut paths = vec![0usize; operators.len()];
+        paths[program.root()] = 1;
+        for i in (0..operators.len()).rev() {
+            if paths[i] > 1 && matches!(operators[i], Operator::Ambiguity(_)) {
+                return true;
+            }
+            for x in operators[i].dependents().iter().flatten() {
+                paths[*x] = usize::min(paths[*x] + paths[i], 2);
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn it_preserves_semantics() {
+        let mut rand_gen = XorShiftRng::from_seed([7, 5, 3, 1]);
+        let input_size = 3;
+        for _ in 0..2000 {
+            let size = 1 + rand_gen.gen_range(0, 4);
+            let count = input_size + 1 + rand_gen.gen_range(0, 10);
+            let mut operators = vec![Operator::Initial; input_size];
+            for i in input_size..count {
+                operators.push(Operator::new_rand(&mut rand_gen, i));
+            }
+            let program = Program::new(operators, input_size).unwrap();
+            let simple = simplify(&program, size);
+            assert!(simple.len() <= program.len());
+            for _ in 0..4 {
+                let inputs: Vec<usize> = (0..input_size).map(|_| rand_gen.gen_range(0, size)).collect();
+                let expected = exact_root(&program, size, &inputs);
+                let actual = exact_root(&simple, size, &inputs);
+                for (p, q) in expected.iter().zip(actual.iter()) {
+                    assert!((p - q).abs() < 1e-5, "{:?} simplified to {:?} with size {} and inputs {:?}",
+                            program, simple, size, inputs);
+                }
+                if !reuses_ambiguity(&program) {
+                    let expected = analytic_root(&program, size, &inputs);
+                    let actual = analytic_root(&simple, size, &inputs);
+                    for (p, q) in expected.iter().zip(actual.iter()) {
+                        assert!((p - q).abs() < 1e-5, "{:?} simplified to {:?} analytically with size {} and inputs {:?}",
+                                program, simple, size, inputs);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! An optional type system for computed, so that random generation only builds well-typed
+//! programs.
+//!
+//! Every computed has a `Type`. Inputs are given their types by the user, and every other
+//! operator has a signature. Constants are the exception: a `Value` is accepted wherever its value
+//! makes sense, so `input[0] == 2` is well-typed even if `input[0]` is an `Enum`.
+use std::collections::{HashMap};
+use rand;
+use super::operator::{Categorical, Operator};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// 0 or 1, as produced by `Equality`, `And`, `Or` and `Not`.
+    Bool,
+    /// A number in the domain, which can be incremented.
+    Int,
+    /// The values of input variable `v`, which can only be compared with each other.
+    Enum(usize),
+}
+
+/// Whether a computed can be used where `expected` is required.
+fn accepts(operators: &[Operator], types: &[Type], x: usize, expected: Type) -> bool {
+    match operators[x] {
+        Operator::Value(v) => expected != Type::Bool || v <= 1,
+        _ => types[x] == expected,
+    }
+}
+
+/// The type two computed share, if they can be compared or used as the branches of an `Ite`.
+fn unify(operators: &[Operator], types: &[Type], x: usize, y: usize) -> Option<Type> {
+    if accepts(operators, types, x, types[y]) {
+        Some(types[y])
+    } else if accepts(operators, types, y, types[x]) {
+        Some(types[x])
+    } else {
+        None
+    }
+}
+
+/// Infers the type of a computed from the types of the computed before it, or returns `None` if
+/// it is ill-typed. `Initial` operators have no signature, so also return `None`.
+pub fn infer(operators: &[Operator], types: &[Type], computed: usize) -> Option<Type> {
+    infer_operator(operators[computed], operators, types)
+}
+
+/// Infers the type an operator would have if it were added after the computed in `operators`.
+pub fn infer_operator(operator: Operator, operators: &[Operator], types: &[Type]) -> Option<Type> {
+    let is = |x: usize, t: Type| accepts(operators, types, x, t);
+    match operator {
+        Operator::Initial => None,
+        Operator::Value(v) => Some(if v <= 1 { Type::Bool } else { Type::Int }),
+        Operator::Ambiguity(c) => Some(if c.support() <= 2 { Type::Bool } else { Type::Int }),
+        Operator::Equality(x, y) => unify(operators, types, x, y).map(|_| Type::Bool),
+        Operator::Increment(x) if is(x, Type::Int) => Some(Type::Int),
+        Operator::And(x, y) | Operator::Or(x, y) if is(x, Type::Bool) && is(y, Type::Bool) => Some(Type::Bool),
+        Operator::Not(x) if is(x, Type::Bool) => Some(Type::Bool),
+        Operator::Ite(c, a, b) if is(c, Type::Bool) => unify(operators, types, a, b),
+        _ => None,
+    }
+}
+
+/// Infers the types of every operator after `input_types.len()` inputs, or returns the index of
+/// the first ill-typed operator.
+pub fn infer_all(operators: &[Operator], input_types: &[Type]) -> Result<Vec<Type>, usize> {
+    let mut types = input_types.to_vec();
+    for i in input_types.len()..operators.len() {
+        types.push(infer(operators, &types, i).ok_or(i)?);
+    }
+    Ok(types)
+}
+
+/// The computed generated so far, indexed by the types they can be used as, so that typed
+/// generation doesn't scan the whole population for every argument.
+#[derive(Clone, Debug, Default)]
+pub struct Candidates {
+    /// Every computed so far.
+    count: usize,
+    /// Constants 0 and 1, which are accepted as any type.
+    small_values: Vec<usize>,
+    /// Larger constants, which are accepted as any type but `Bool`.
+    large_values: Vec<usize>,
+    /// The other computed, by type.
+    by_type: HashMap<Type, Vec<usize>>,
+    /// The other computed which aren't `Bool`.
+    not_bool: Vec<usize>,
+}
+
+impl Candidates {
+    /// Indexes computed `0..count`.
+    pub fn new(operators: &[Operator], types: &[Type], count: usize) -> Self {
+        let mut candidates = Candidates::default();
+        for i in 0..count {
+            candidates.push(operators[i], types[i]);
+        }
+        candidates
+    }
+
+    /// Indexes the next computed.
+    pub fn push(&mut self, operator: Operator, t: Type) {
+        let i = self.count;
+        match operator {
+            Operator::Value(v) if v <= 1 => self.small_values.push(i),
+            Operator::Value(_) => self.large_values.push(i),
+            _ => {
+                self.by_type.entry(t).or_default().push(i);
+                if t != Type::Bool {
+                    self.not_bool.push(i);
+                }
+            },
+        }
+        self.count += 1;
+    }
+
+    /// The computed which can be used where `expected` is required.
+    fn accepted(&self, expected: Type) -> [&[usize]; 3] {
+        let typed = self.by_type.get(&expected).map_or(&[][..], |computed| computed);
+        if expected == Type::Bool {
+            [&self.small_values, typed, &[]]
+        } else {
+            [&self.small_values, &self.large_values, typed]
+        }
+    }
+
+    /// The computed which unify with `x`, or `None` if every computed does.
+    fn unifiable(&self, operators: &[Operator], types: &[Type], x: usize) -> Option<[&[usize]; 3]> {
+        match operators[x] {
+            Operator::Value(v) if v <= 1 => None,
+            Operator::Value(_) => Some([&self.small_values, &self.large_values, &self.not_bool]),
+            _ => Some(self.accepted(types[x])),
+        }
+    }
+}
+
+/// Picks a random computed from the concatenation of `groups`.
+fn pick<R>(rand_gen: &mut R, groups: [&[usize]; 3]) -> Option<usize> where R: rand::Rng {
+    let total: usize = groups.iter().map(|group| group.len()).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut n = rand_gen.next_u32() as usize % total;
+    for group in groups.iter() {
+        if n < group.len() {
+            return Some(group[n]);
+        }
+        n -= group.len();
+    }
+    unreachable!("n is less than the total length of the groups")
+}
+
+/// Picks a random computed before `output_idx` which unifies with `x`.
+fn pick_unifiable<R>(rand_gen: &mut R, candidates: &Candidates, operators: &[Operator], types: &[Type], x: usize,
+                     output_idx: usize) -> Option<usize> where R: rand::Rng {
+    match candidates.unifiable(operators, types, x) {
+        Some(groups) => pick(rand_gen, groups),
+        None => Some(rand_gen.next_u32() as usize % output_idx),
+    }
+}
+
+/// Generates a random well-typed operator at index `output_idx`, with the same mix of operators
+/// as `Operator::new_rand`, but with arguments chosen among the type-compatible computed, and
+/// random choices made by `ambiguity`. `candidates` must index exactly the computed before
+/// `output_idx`.
+pub fn new_rand_typed<R>(rand_gen: &mut R, operators: &[Operator], types: &[Type], candidates: &Candidates,
+                         ambiguity: Categorical, output_idx: usize) -> (Operator, Type) where R: rand::Rng {
+    let bools = candidates.accepted(Type::Bool);
+    // Some operators may have no compatible arguments yet, so try a few times.
+    for _ in 0..16 {
+        let operator = match Operator::new_rand(rand_gen, output_idx) {
+            Operator::Equality(_, _) => {
+                let x = rand_gen.next_u32() as usize % output_idx;
+                pick_unifiable(rand_gen, candidates, operators, types, x, output_idx).map(|y| Operator::Equality(x, y))
+            },
+            Operator::Increment(_) => pick(rand_gen, candidates.accepted(Type::Int)).map(Operator::Increment),
+            Operator::And(_, _) => pick(rand_gen, bools)
+                .and_then(|x| pick(rand_gen, bools).map(|y| Operator::And(x, y))),
+            Operator::Or(_, _) => pick(rand_gen, bools)
+                .and_then(|x| pick(rand_gen, bools).map(|y| Operator::Or(x, y))),
+            Operator::Not(_) => pick(rand_gen, bools).map(Operator::Not),
+            Operator::Ite(_, _, _) => pick(rand_gen, bools).and_then(|c| {
+                let a = rand_gen.next_u32() as usize % output_idx;
+                pick_unifiable(rand_gen, candidates, operators, types, a, output_idx).map(|b| Operator::Ite(c, a, b))
+            }),
+            Operator::Ambiguity(_) => Some(Operator::Ambiguity(ambiguity)),
+            leaf => Some(leaf),
+        };
+        if let Some(operator) = operator {
+            let t = infer_operator(operator, operators, types).expect("generated operators are well-typed");
+            return (operator, t);
+        }
+    }
+    (Operator::Value(0), Type::Bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, XorShiftRng};
+    use super::*;
+    use super::super::operator::Operator::*;
+
+    // The winner, the player to move, and a counter.
+    const INPUTS: [Type; 3] = [Type::Enum(0), Type::Bool, Type::Int];
+
+    #[test]
+    fn it_infers_types() {
+        let base = [Initial, Initial, Initial, Value(2), Equality(2, 3)];
+        let types = infer_all(&base, &INPUTS).unwrap();
+        assert_eq!(types[3..], [Type::Int, Type::Bool]);
+        let infer_last = |operator: Operator| infer_operator(operator, &base, &types);
+        assert_eq!(infer_last(Equality(0, 3)), Some(Type::Bool));
+        assert_eq!(infer_last(Ite(4, 2, 3)), Some(Type::Int));
+        assert_eq!(infer_last(Ite(1, 0, 3)), Some(Type::Enum(0)));
+        assert_eq!(infer_last(Increment(4)), None);
+        assert_eq!(infer_last(And(2, 1)), None);
+        assert_eq!(infer_last(Equality(0, 2)), None);
+        assert_eq!(infer_last(Ite(4, 0, 1)), None);
+        assert_eq!(infer_all(&[Initial, Initial, Initial, Not(0)], &INPUTS), Err(3));
+    }
+
+    #[test]
+    fn it_generates_well_typed_operators() {
+        let mut rand_gen = XorShiftRng::from_seed([1, 1, 2, 3]);
+        let mut operators = vec![Initial; 3];
+        let mut types = INPUTS.to_vec();
+        let mut candidates = Candidates::new(&operators, &types, 3);
+        for i in 3..500 {
+            let (operator, t) = new_rand_typed(&mut rand_gen, &operators, &types, &candidates, Categorical::COIN, i);
+            operators.push(operator);
+            types.push(t);
+            candidates.push(operator, t);
+        }
+        assert_eq!(infer_all(&operators, &INPUTS), Ok(types.clone()));
+        assert!(operators.iter().any(|op| matches!(*op, Ite(_, _, _))));
+        // The index holds exactly the computed a scan would find.
+        let sorted = |groups: [&[usize]; 3]| {
+            let mut computed: Vec<usize> = groups.iter().flat_map(|group| group.iter().cloned()).collect();
+            computed.sort();
+            computed
+        };
+        for &t in INPUTS.iter() {
+            let scanned: Vec<usize> = (0..500).filter(|&x| accepts(&operators, &types, x, t)).collect();
+            assert_eq!(sorted(candidates.accepted(t)), scanned);
+        }
+        for x in 0..500 {
+            let scanned: Vec<usize> = (0..500).filter(|&y| unify(&operators, &types, x, y).is_some()).collect();
+            let indexed = candidates.unifiable(&operators, &types, x).map_or((0..500).collect(), sorted);
+            assert_eq!(indexed, scanned, "unifying with {:?}", operators[x]);
+        }
+    }
+}
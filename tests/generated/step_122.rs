@@ -0,0 +1,16 @@
+/// Computes the next state. Values are in `0..3`, and 3 is undefined.
+pub fn step<R>(state: &[usize], _rng: &mut R) -> Vec<usize> where R: FnMut(&[f32]) -> usize {
+    const UNDEFINED: usize = 3;
+    // output 0
+    let o0_3 = 2;
+    let o0_4 = if state[2] == UNDEFINED || o0_3 == UNDEFINED { UNDEFINED } else if state[2] == o0_3 { 1 } else { 0 };
+    let o0_5 = if o0_4 == UNDEFINED { UNDEFINED } else if o0_4 != 0 { state[1] } else { state[0] };
+    // output 1
+    let o1_3 = if state[1] == UNDEFINED { UNDEFINED } else if state[1] != 0 { 0 } else { 1 };
+    // output 2
+    let o2_3 = 2;
+    let o2_4 = if state[2] == UNDEFINED || o2_3 == UNDEFINED { UNDEFINED } else if state[2] == o2_3 { 1 } else { 0 };
+    let o2_5 = ::std::cmp::min(state[2] + 1, UNDEFINED);
+    let o2_6 = if o2_4 == UNDEFINED { UNDEFINED } else if o2_4 != 0 { state[2] } else { o2_5 };
+    vec![o0_5, o1_3, o2_6]
+}